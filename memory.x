@@ -1,6 +1,7 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 512K
+  /* The last 32K are left for the settings, see storage.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 480K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
@@ -1,32 +1,42 @@
-use crate::twim::{TWIN_CHANNEL, TwinCommand};
+use crate::{
+    expander::Headlight,
+    twim::{TwinClient, TwinError, TwinPriority},
+};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
 
 pub static BIG_LEDS_CHANNEL: Channel<ThreadModeRawMutex, BigLedCommand, 1> = Channel::new();
+// Only the big LEDs task talks to the LEDs
+static BIG_LEDS_TWIN: TwinClient = TwinClient::new(TwinPriority::Lighting);
 
+#[derive(Clone, Copy)]
 pub enum BigLedCommand {
     Toggle,
+    Brightness(u8),
 }
 
 pub struct BigLed {
-    channel: u8,
+    headlight: Headlight,
     value: u8,
 }
 
 impl BigLed {
-    const LEFT_LED: Self = Self::new(0x09, 0x00);
-    const RIGHT_LED: Self = Self::new(0x0A, 0x00);
+    const LEFT_LED: Self = Self::new(Headlight::Left, 0x00);
+    const RIGHT_LED: Self = Self::new(Headlight::Right, 0x00);
 
-    const fn new(channel: u8, value: u8) -> Self {
-        Self { channel, value }
+    const fn new(headlight: Headlight, value: u8) -> Self {
+        Self { headlight, value }
     }
     pub const fn all_leds() -> [Self; 2] {
         [Self::LEFT_LED, Self::RIGHT_LED]
     }
 
-    pub async fn set_value(&mut self, value: u8) {
+    pub async fn set_value(&mut self, value: u8) -> Result<(), TwinError> {
         self.value = value;
-        TWIN_CHANNEL
-            .send(TwinCommand::new(self.channel, value))
-            .await;
+        BIG_LEDS_TWIN.set_headlight(self.headlight, value).await
+    }
+
+    pub async fn read_value(&mut self) -> Result<u8, TwinError> {
+        self.value = BIG_LEDS_TWIN.headlight(self.headlight).await?;
+        Ok(self.value)
     }
 }
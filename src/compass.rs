@@ -0,0 +1,295 @@
+use crate::{
+    math::{atan2, sqrt},
+    motion_sensor::{Acceleration, MagneticField},
+    storage::{Record, Slot},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, watch::Watch};
+
+// Clockwise from magnetic north in radians, -PI..=PI, with every new magnetometer sample
+pub static HEADING: Watch<ThreadModeRawMutex, f32, 4> = Watch::new();
+// Applied to the magnetometer before the heading is worked out
+pub static COMPASS_CALIBRATION: Watch<ThreadModeRawMutex, CompassCalibration, 1> =
+    Watch::new_with(CompassCalibration::DEFAULT);
+
+// The micro:bit stands in its slot with the LEDs to the front of the car, in the axes
+// of the motion sensor
+const CAR_FORWARD: [f32; 3] = [0.0, 0.0, 1.0];
+// Falling, or the sensor isn't there: no idea where down is
+const MIN_GRAVITY_MG: f32 = 300.0;
+// Horizontal part of the field seen by the forward axis, less when it points nearly
+// straight up or down
+const MIN_HORIZONTAL_UT: f32 = 1.0;
+// Spinning flat only turns the axes lying level through the field, the earth gives them
+// about 40µT between the extremes. The axis pointing up hardly changes and keeps its offset
+const MIN_CALIBRATION_SPAN_UT: f32 = 15.0;
+
+// Hard iron shifts the field the car sees, soft iron stretches it differently on every axis
+#[derive(Clone, Copy, defmt::Format)]
+pub struct CompassCalibration {
+    // In µT, taken off the readings
+    pub offset: [f32; 3],
+    // The readings are multiplied by it after the offset
+    pub scale: [f32; 3],
+}
+
+impl CompassCalibration {
+    pub const DEFAULT: Self = Self {
+        offset: [0.0; 3],
+        scale: [1.0; 3],
+    };
+
+    pub fn current() -> Self {
+        COMPASS_CALIBRATION
+            .try_get()
+            .unwrap_or(CompassCalibration::DEFAULT)
+    }
+
+    pub fn apply(&self, field: &MagneticField) -> MagneticField {
+        MagneticField {
+            x: (field.x - self.offset[0]) * self.scale[0],
+            y: (field.y - self.offset[1]) * self.scale[1],
+            z: (field.z - self.offset[2]) * self.scale[2],
+        }
+    }
+}
+
+impl Record for CompassCalibration {
+    const SLOT: Slot = Slot::CompassCalibration;
+    const SIZE: usize = 6 * 4;
+
+    fn encode(&self, buf: &mut [u8]) {
+        let values = self.offset.iter().chain(&self.scale);
+        for (value, chunk) in values.zip(buf.as_chunks_mut::<4>().0) {
+            *chunk = value.to_le_bytes();
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut calibration = Self::DEFAULT;
+        let values = calibration.offset.iter_mut().chain(&mut calibration.scale);
+        for (value, chunk) in values.zip(buf.as_chunks::<4>().0) {
+            *value = f32::from_le_bytes(*chunk);
+        }
+        // An erased or torn page could leave a scale nobody can use
+        if calibration
+            .scale
+            .iter()
+            .any(|scale| !scale.is_finite() || *scale <= 0.0)
+        {
+            return None;
+        }
+        Some(calibration)
+    }
+}
+
+// Collects the extremes of every axis while the car spins
+pub struct CompassCalibrator {
+    base: CompassCalibration,
+    min: [f32; 3],
+    max: [f32; 3],
+}
+
+impl CompassCalibrator {
+    // Axes that don't turn through the field keep their offset from `base`
+    pub fn new(base: CompassCalibration) -> Self {
+        Self {
+            base,
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        }
+    }
+
+    // Straight from the magnetometer, without any calibration
+    pub fn sample(&mut self, field: &MagneticField) {
+        for (i, value) in [field.x, field.y, field.z].into_iter().enumerate() {
+            self.min[i] = self.min[i].min(value);
+            self.max[i] = self.max[i].max(value);
+        }
+    }
+
+    // None unless at least two axes went all the way round
+    pub fn finish(&self) -> Option<CompassCalibration> {
+        let spans: [f32; 3] = core::array::from_fn(|i| self.max[i] - self.min[i]);
+        let turned = spans.map(|span| span >= MIN_CALIBRATION_SPAN_UT);
+        let turned_count = turned.iter().filter(|turned| **turned).count();
+        if turned_count < 2 {
+            return None;
+        }
+        // Every turned axis gets stretched to the average span
+        let average =
+            (0..3).filter(|&i| turned[i]).map(|i| spans[i]).sum::<f32>() / turned_count as f32;
+
+        let mut calibration = self.base;
+        for i in 0..3 {
+            if turned[i] {
+                calibration.offset[i] = (self.min[i] + self.max[i]) / 2.0;
+                calibration.scale[i] = average / spans[i];
+            } else {
+                calibration.scale[i] = 1.0;
+            }
+        }
+        Some(calibration)
+    }
+}
+
+// Where the front of the car points, whatever the tilt. The accelerometer feels gravity
+// pointing up, so field × gravity points east and gravity × east points north, both
+// level with the ground
+pub fn heading(field: &MagneticField, gravity: &Acceleration) -> Option<f32> {
+    let field = [field.x, field.y, field.z];
+    let gravity = [gravity.x as f32, gravity.y as f32, gravity.z as f32];
+    let gravity_length = sqrt(dot(gravity, gravity));
+    if gravity_length < MIN_GRAVITY_MG {
+        return None;
+    }
+    let east = cross(field, gravity);
+    let north = cross(gravity, east);
+
+    // East is longer than the field by the length of gravity, north by its square
+    let east_part = dot(CAR_FORWARD, east) / gravity_length;
+    let north_part = dot(CAR_FORWARD, north) / (gravity_length * gravity_length);
+    if east_part.abs() + north_part.abs() < MIN_HORIZONTAL_UT {
+        return None;
+    }
+    Some(atan2(east_part, north_part))
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_PI_2;
+
+    // The car stands level when gravity is felt along y, the field points north and
+    // down like in Europe
+    const LEVEL: [f32; 3] = [0.0, 1000.0, 0.0];
+    const FACING_NORTH: [f32; 3] = [0.0, -40.0, 20.0];
+    const FACING_EAST: [f32; 3] = [20.0, -40.0, 0.0];
+    const FACING_WEST: [f32; 3] = [-20.0, -40.0, 0.0];
+
+    // Pitches the car nose up by `angle`, around the sideways axis
+    fn pitch(vector: [f32; 3], angle: f32) -> [f32; 3] {
+        let (sin, cos) = (crate::math::sin(angle), crate::math::cos(angle));
+        [
+            vector[0],
+            vector[1] * cos - vector[2] * sin,
+            vector[1] * sin + vector[2] * cos,
+        ]
+    }
+
+    fn heading_of(field: [f32; 3], gravity: [f32; 3]) -> Option<f32> {
+        let field = MagneticField {
+            x: field[0],
+            y: field[1],
+            z: field[2],
+        };
+        let gravity = Acceleration {
+            x: gravity[0] as i16,
+            y: gravity[1] as i16,
+            z: gravity[2] as i16,
+        };
+        heading(&field, &gravity)
+    }
+
+    fn assert_close(heading: Option<f32>, expected: f32) {
+        let heading = heading.unwrap();
+        assert!((heading - expected).abs() < 0.02, "{heading} vs {expected}");
+    }
+
+    #[test]
+    fn heading_on_level_ground() {
+        assert_close(heading_of(FACING_NORTH, LEVEL), 0.0);
+        assert_close(heading_of(FACING_EAST, LEVEL), FRAC_PI_2);
+        assert_close(heading_of(FACING_WEST, LEVEL), -FRAC_PI_2);
+    }
+
+    #[test]
+    fn heading_holds_when_the_car_is_tilted() {
+        for angle in [-0.5, -0.2, 0.3, 0.6] {
+            let gravity = pitch(LEVEL, angle);
+            assert_close(heading_of(pitch(FACING_NORTH, angle), gravity), 0.0);
+            assert_close(heading_of(pitch(FACING_EAST, angle), gravity), FRAC_PI_2);
+        }
+    }
+
+    #[test]
+    fn no_heading_while_falling() {
+        assert!(heading_of(FACING_NORTH, [0.0, 100.0, 0.0]).is_none());
+    }
+
+    // Spinning flat, x and z go round an ellipse off centre while y stays put
+    fn spin(calibrator: &mut CompassCalibrator, radius: [f32; 2]) {
+        for step in 0..72 {
+            let angle = step as f32 * 5.0_f32.to_radians();
+            calibrator.sample(&MagneticField {
+                x: 10.0 + radius[0] * crate::math::cos(angle),
+                y: -40.0,
+                z: -5.0 + radius[1] * crate::math::sin(angle),
+            });
+        }
+    }
+
+    #[test]
+    fn calibration_centres_and_evens_out_the_turned_axes() {
+        let base = CompassCalibration {
+            offset: [1.0, 2.0, 3.0],
+            scale: [2.0, 2.0, 2.0],
+        };
+        let mut calibrator = CompassCalibrator::new(base);
+        spin(&mut calibrator, [30.0, 20.0]);
+        let calibration = calibrator.finish().unwrap();
+
+        let expected_offset = [10.0, 2.0, -5.0];
+        let expected_scale = [50.0 / 60.0, 1.0, 50.0 / 40.0];
+        for i in 0..3 {
+            assert!((calibration.offset[i] - expected_offset[i]).abs() < 0.01);
+            assert!((calibration.scale[i] - expected_scale[i]).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn calibration_needs_two_axes_turned_through_the_field() {
+        let mut calibrator = CompassCalibrator::new(CompassCalibration::DEFAULT);
+        spin(&mut calibrator, [30.0, 5.0]);
+        assert!(calibrator.finish().is_none());
+        assert!(
+            CompassCalibrator::new(CompassCalibration::DEFAULT)
+                .finish()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn calibration_record_round_trip() {
+        let calibration = CompassCalibration {
+            offset: [12.5, -3.25, 0.0],
+            scale: [0.8, 1.0, 1.25],
+        };
+        let mut buf = [0u8; CompassCalibration::SIZE];
+        calibration.encode(&mut buf);
+        let decoded = CompassCalibration::decode(&buf).unwrap();
+        assert_eq!(decoded.offset, calibration.offset);
+        assert_eq!(decoded.scale, calibration.scale);
+    }
+
+    #[test]
+    fn calibration_record_rejects_unusable_scales() {
+        // An erased page reads as NaN
+        assert!(CompassCalibration::decode(&[0xFF; CompassCalibration::SIZE]).is_none());
+        let mut buf = [0u8; CompassCalibration::SIZE];
+        CompassCalibration::DEFAULT.encode(&mut buf);
+        buf[12..16].copy_from_slice(&0.0_f32.to_le_bytes());
+        assert!(CompassCalibration::decode(&buf).is_none());
+    }
+}
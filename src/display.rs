@@ -0,0 +1,67 @@
+use crate::motor::Direction;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+
+// Brightness of a pixel goes from 0 (off) to MAX_BRIGHTNESS
+pub const MAX_BRIGHTNESS: u8 = (1 << BRIGHTNESS_BITS) - 1;
+pub const BRIGHTNESS_BITS: u8 = 3;
+
+// Anything can ask for the display, the last command wins
+pub static DISPLAY_CHANNEL: Channel<ThreadModeRawMutex, DisplayCommand, 4> = Channel::new();
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum DisplayCommand {
+    Clear,
+    Image(Image),
+    Icon(Icon),
+    // A single character stands still, longer text scrolls through once
+    Text(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Image {
+    // Brightness of every pixel, row by row from the top left
+    pub pixels: [[u8; 5]; 5],
+}
+
+impl Image {
+    pub const BLANK: Self = Self {
+        pixels: [[0; 5]; 5],
+    };
+
+    // Fills up row by row from 0 to 0xFF, the last pixel lit gets brighter on its way
+    pub fn progress(progress: u8) -> Self {
+        let lit = progress as usize * 25 * MAX_BRIGHTNESS as usize / 0xFF;
+        let mut image = Self::BLANK;
+        for (i, pixel) in image.pixels.as_flattened_mut().iter_mut().enumerate() {
+            let level = lit.saturating_sub(i * MAX_BRIGHTNESS as usize);
+            *pixel = level.min(MAX_BRIGHTNESS as usize) as u8;
+        }
+        image
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Icon {
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    Stop,
+    Tick,
+    Cross,
+    // Low, the car board has no power
+    Battery,
+}
+
+impl Icon {
+    // Where the car is driven, seen from behind it
+    pub fn direction(direction: Direction) -> Self {
+        match direction {
+            Direction::Stopped => Icon::Stop,
+            Direction::Forward => Icon::ArrowUp,
+            Direction::Backward => Icon::ArrowDown,
+            Direction::StrafeLeft | Direction::TurnLeft => Icon::ArrowLeft,
+            Direction::StrafeRight | Direction::TurnRight => Icon::ArrowRight,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use embedded_hal_async::i2c::I2c;
+
+// Motor and headlight controller of the expansion board
+pub const EXPANDER_ADDRESS: u8 = 0x30;
+
+// The expander moves to the next register by itself when several values are written
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Register {
+    FrontRightA = 0x01,
+    FrontRightB = 0x02,
+    FrontLeftA = 0x03,
+    FrontLeftB = 0x04,
+    BackRightA = 0x05,
+    BackRightB = 0x06,
+    BackLeftA = 0x07,
+    BackLeftB = 0x08,
+    LeftHeadlight = 0x09,
+    RightHeadlight = 0x0A,
+}
+
+impl Register {
+    pub const COUNT: usize = 10;
+    pub const ALL: [Self; Self::COUNT] = [
+        Register::FrontRightA,
+        Register::FrontRightB,
+        Register::FrontLeftA,
+        Register::FrontLeftB,
+        Register::BackRightA,
+        Register::BackRightB,
+        Register::BackLeftA,
+        Register::BackLeftB,
+        Register::LeftHeadlight,
+        Register::RightHeadlight,
+    ];
+
+    pub const fn address(self) -> u8 {
+        self as u8
+    }
+
+    // Position in Register::ALL
+    pub const fn index(self) -> usize {
+        (self.address() - Register::FrontRightA.address()) as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Wheel {
+    FrontRight,
+    FrontLeft,
+    BackRight,
+    BackLeft,
+}
+
+// Values for both inputs of a motor driver: (0, speed) forward, (speed, 0) backward
+pub type WheelInputs = (u8, u8);
+pub const WHEEL_STOPPED: WheelInputs = (0x00, 0x00);
+// Both inputs high short the motor, it stops turning much sooner than coasting
+pub const WHEEL_BRAKED: WheelInputs = (0xFF, 0xFF);
+
+impl Wheel {
+    // Same order as the registers
+    pub const ALL: [Self; 4] = [
+        Wheel::FrontRight,
+        Wheel::FrontLeft,
+        Wheel::BackRight,
+        Wheel::BackLeft,
+    ];
+
+    // Position in Wheel::ALL
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    pub const fn registers(self) -> (Register, Register) {
+        match self {
+            Wheel::FrontRight => (Register::FrontRightA, Register::FrontRightB),
+            Wheel::FrontLeft => (Register::FrontLeftA, Register::FrontLeftB),
+            Wheel::BackRight => (Register::BackRightA, Register::BackRightB),
+            Wheel::BackLeft => (Register::BackLeftA, Register::BackLeftB),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Headlight {
+    Left,
+    Right,
+}
+
+impl Headlight {
+    pub const fn register(self) -> Register {
+        match self {
+            Headlight::Left => Register::LeftHeadlight,
+            Headlight::Right => Register::RightHeadlight,
+        }
+    }
+}
+
+// Works on any async I2C bus, the car uses the TWIM of the edge connector
+pub struct Expander<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Expander<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    // Stop every wheel, the expander keeps its registers over a reset of the micro:bit.
+    // The headlights are left as they are
+    pub async fn init(&mut self) -> Result<(), I2C::Error> {
+        self.set_wheels([WHEEL_STOPPED; 4]).await
+    }
+
+    pub async fn write(&mut self, register: Register, value: u8) -> Result<(), I2C::Error> {
+        self.i2c
+            .write(EXPANDER_ADDRESS, &[register.address(), value])
+            .await
+    }
+
+    pub async fn read(&mut self, register: Register) -> Result<u8, I2C::Error> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(EXPANDER_ADDRESS, &[register.address()], &mut value)
+            .await?;
+        Ok(value[0])
+    }
+
+    // All the wheels in a single transaction, so they change at once. Same order as Wheel::ALL
+    pub async fn set_wheels(&mut self, inputs: [WheelInputs; 4]) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; 1 + 2 * 4];
+        buffer[0] = Register::FrontRightA.address();
+        for (wheel, (input_a, input_b)) in Wheel::ALL.into_iter().zip(inputs) {
+            let (register_a, register_b) = wheel.registers();
+            buffer[1 + register_a.index()] = input_a;
+            buffer[1 + register_b.index()] = input_b;
+        }
+        self.i2c.write(EXPANDER_ADDRESS, &buffer).await
+    }
+
+    pub async fn set_headlight(
+        &mut self,
+        headlight: Headlight,
+        value: u8,
+    ) -> Result<(), I2C::Error> {
+        self.write(headlight.register(), value).await
+    }
+
+    pub async fn headlight(&mut self, headlight: Headlight) -> Result<u8, I2C::Error> {
+        self.read(headlight.register()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    // Behaves like the expander: a write starts at the register in its first byte and
+    // goes on with the next ones, a read carries on from there
+    struct MockExpander {
+        registers: [u8; 0x10],
+        pointer: usize,
+        // Bytes of every write, one entry per transaction
+        writes: Vec<Vec<u8>>,
+        absent: bool,
+    }
+
+    impl MockExpander {
+        fn new() -> Self {
+            Self {
+                registers: [0; 0x10],
+                pointer: 0,
+                writes: Vec::new(),
+                absent: false,
+            }
+        }
+
+        fn register(&self, register: Register) -> u8 {
+            self.registers[register.address() as usize]
+        }
+    }
+
+    impl ErrorType for MockExpander {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockExpander {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            if self.absent || address != EXPANDER_ADDRESS {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+            let mut written = Vec::new();
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        if let Some((&register, values)) = bytes.split_first() {
+                            self.pointer = register as usize;
+                            for &value in values {
+                                self.registers[self.pointer] = value;
+                                self.pointer += 1;
+                            }
+                        }
+                        written.extend_from_slice(bytes);
+                    }
+                    Operation::Read(buffer) => {
+                        for value in buffer.iter_mut() {
+                            *value = self.registers[self.pointer];
+                            self.pointer += 1;
+                        }
+                    }
+                }
+            }
+            if !written.is_empty() {
+                self.writes.push(written);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn set_wheels_writes_every_wheel_in_one_transaction() {
+        let mut expander = Expander::new(MockExpander::new());
+        let inputs = [(0x00, 0x10), (0x20, 0x00), (0x00, 0x30), (0xFF, 0xFF)];
+        block_on(expander.set_wheels(inputs)).unwrap();
+
+        let bus = &expander.i2c;
+        assert_eq!(bus.writes.len(), 1);
+        for (wheel, (input_a, input_b)) in Wheel::ALL.into_iter().zip(inputs) {
+            let (register_a, register_b) = wheel.registers();
+            assert_eq!(bus.register(register_a), input_a);
+            assert_eq!(bus.register(register_b), input_b);
+        }
+        assert_eq!(bus.register(Register::LeftHeadlight), 0x00);
+    }
+
+    #[test]
+    fn init_stops_the_wheels_and_leaves_the_headlights() {
+        let mut bus = MockExpander::new();
+        bus.registers[Register::FrontLeftB.address() as usize] = 0x80;
+        bus.registers[Register::RightHeadlight.address() as usize] = 0x40;
+        let mut expander = Expander::new(bus);
+        block_on(expander.init()).unwrap();
+
+        let bus = &expander.i2c;
+        for register in &Register::ALL[..8] {
+            assert_eq!(bus.register(*register), 0x00);
+        }
+        assert_eq!(bus.register(Register::RightHeadlight), 0x40);
+    }
+
+    #[test]
+    fn headlights_use_their_own_registers() {
+        let mut expander = Expander::new(MockExpander::new());
+        block_on(expander.set_headlight(Headlight::Left, 0x12)).unwrap();
+        block_on(expander.set_headlight(Headlight::Right, 0x34)).unwrap();
+
+        assert_eq!(
+            expander.i2c.writes,
+            [
+                vec![Register::LeftHeadlight.address(), 0x12],
+                vec![Register::RightHeadlight.address(), 0x34],
+            ]
+        );
+        assert_eq!(block_on(expander.headlight(Headlight::Left)), Ok(0x12));
+        assert_eq!(block_on(expander.headlight(Headlight::Right)), Ok(0x34));
+    }
+
+    #[test]
+    fn bus_errors_reach_the_caller() {
+        let mut bus = MockExpander::new();
+        bus.absent = true;
+        let mut expander = Expander::new(bus);
+        let nack = Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+
+        assert_eq!(block_on(expander.set_wheels([WHEEL_BRAKED; 4])), nack);
+        assert_eq!(
+            block_on(expander.headlight(Headlight::Left)),
+            nack.map(|()| 0)
+        );
+    }
+}
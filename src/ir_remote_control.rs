@@ -1,9 +1,19 @@
 use crate::{
     big_led::{BIG_LEDS_CHANNEL, BigLedCommand},
-    motor::{MOTORS_CHANNEL, MotorCommand},
+    expander::Wheel,
+    line_follow::{LINE_FOLLOW_TUNING, LineFollowTuning},
+    mode::Mode,
+    motor::{
+        MOTORS_CHANNEL, ManoeuvreEnd, MotorCommand, MotorSide, Steering, StopMode,
+        WHEEL_TRIM_CHANNEL, WheelTrimCommand,
+    },
+    recorder::{Action, FIRST_RECORDING_KEY, RECORDER_CHANNEL, RECORDING_COUNT, RecorderEvent},
+    safety::SafetyEvent,
 };
 use defmt::debug;
+use embassy_time::Instant;
 
+#[derive(Clone, Copy)]
 pub enum IrButton {
     Ok,
     Left,
@@ -67,38 +77,226 @@ pub trait IrButtonHandler {
     fn on_unknown(&mut self, cmd: u8);
 }
 
-pub struct IrRemoteController;
+// Repeat codes come about every 110ms while a button is held
+const OK_HOLD_REPEATS: u8 = 3;
+const NUM_HOLD_REPEATS: u8 = 5;
+
+// What the arrows do in manual mode, Hash switches between them
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DriveMapping {
+    // Up and Down drive, Left and Right spin on the spot
+    Tank,
+    // Up and Down step the throttle, Left and Right step the steering angle
+    Car,
+}
+
+pub struct IrRemoteController {
+    mapping: DriveMapping,
+    steering: Steering,
+    last_button: Option<IrButton>,
+    repeats: u8,
+    // Ok was held, the next tap releases the brake
+    braking: bool,
+}
+
+impl IrRemoteController {
+    pub const fn new() -> Self {
+        Self {
+            mapping: DriveMapping::Tank,
+            steering: Steering {
+                throttle: 0,
+                angle: 0,
+            },
+            last_button: None,
+            repeats: 0,
+            braking: false,
+        }
+    }
+
+    pub fn press(&mut self, button: IrButton) {
+        self.last_button = Some(button);
+        self.repeats = 0;
+        // After a crash the car waits for Ok, everything else is ignored
+        if let Some(event) = SafetyEvent::held() {
+            if let IrButton::Ok = button {
+                SafetyEvent::resume();
+                debug!("Ok button pressed: resume after {}", event);
+            }
+            return;
+        }
+        if Mode::current() == Mode::WheelTrim
+            && let Some(command) = Self::trim_command(button)
+        {
+            let _ = WHEEL_TRIM_CHANNEL.try_send(command);
+            return;
+        }
+        if Mode::current() == Mode::LineFollow
+            && let Some(tuning) = Self::line_follow_tuning(button)
+        {
+            let _ = LINE_FOLLOW_TUNING.try_send(tuning);
+            return;
+        }
+        // While recording, the recording keys save the session instead of replaying one
+        if Mode::current() == Mode::Record
+            && let IrButton::Num(n) = button
+            && (FIRST_RECORDING_KEY..FIRST_RECORDING_KEY + RECORDING_COUNT).contains(&n)
+        {
+            let _ = RECORDER_CHANNEL.try_send(RecorderEvent::Save(n - FIRST_RECORDING_KEY));
+            return;
+        }
+        button.execute(self);
+    }
+
+    fn record(&self, action: Action) {
+        if Mode::current() == Mode::Record {
+            let _ = RECORDER_CHANNEL.try_send(RecorderEvent::Step(Instant::now(), action));
+        }
+    }
+
+    // Left and Right steer the trim, Up and Down test it, 1 to 4 invert a wheel,
+    // Star picks a wheel and Hash changes its deadband, Ok saves. 0 leaves without saving
+    fn trim_command(button: IrButton) -> Option<WheelTrimCommand> {
+        match button {
+            IrButton::Left => Some(WheelTrimCommand::Steer(MotorSide::Left)),
+            IrButton::Right => Some(WheelTrimCommand::Steer(MotorSide::Right)),
+            IrButton::Up => Some(WheelTrimCommand::TestDrive),
+            IrButton::Down => Some(WheelTrimCommand::TestStop),
+            IrButton::Num(n @ 1..=4) => {
+                Some(WheelTrimCommand::ToggleInverted(Wheel::ALL[n as usize - 1]))
+            }
+            IrButton::Star => Some(WheelTrimCommand::SelectNextWheel),
+            IrButton::Hash => Some(WheelTrimCommand::NextDeadband),
+            IrButton::Ok => Some(WheelTrimCommand::Save),
+            _ => None,
+        }
+    }
+
+    // Star tries the next gains, Hash switches between turning and strafing towards
+    // the line. The other keys drive or select a mode as usual
+    fn line_follow_tuning(button: IrButton) -> Option<LineFollowTuning> {
+        match button {
+            IrButton::Star => Some(LineFollowTuning::NextGains),
+            IrButton::Hash => Some(LineFollowTuning::ToggleLateralCorrection),
+            _ => None,
+        }
+    }
+
+    // A tap on Ok brakes and then lets the wheels coast, holding it keeps braking
+    // until the next tap. Some number keys select another mode when held
+    pub fn repeat(&mut self) {
+        self.repeats = self.repeats.saturating_add(1);
+        match self.last_button {
+            Some(IrButton::Ok) if self.repeats == OK_HOLD_REPEATS => {
+                self.drive(MotorCommand::Stop(StopMode::Brake));
+                self.braking = true;
+                debug!("Ok button held: brake");
+            }
+            Some(IrButton::Num(n)) if self.repeats == NUM_HOLD_REPEATS => {
+                if let Some(mode) = Mode::from_held_num(n) {
+                    self.select_mode(mode);
+                    debug!("Number button {} held: mode selected", n);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Whatever the car did before, the new mode starts from standstill. The stop goes
+    // out before the mode changes, so it can't land after the first command of the mode
+    fn select_mode(&mut self, mode: Mode) {
+        self.steering = Steering::default();
+        self.braking = false;
+        Self::send_now(MotorCommand::Stop(StopMode::BrakeThenCoast));
+        Mode::set(mode);
+    }
+
+    // The channel holds a single command, often one the mode being left queued. That one
+    // is dropped instead, so the remote's command can't be lost
+    fn send_now(command: MotorCommand) {
+        while let Ok(queued) = MOTORS_CHANNEL.try_receive() {
+            queued.report(ManoeuvreEnd::Cancelled);
+        }
+        let _ = MOTORS_CHANNEL.try_send(command);
+    }
+
+    // Car mapping: the new steering takes effect right away
+    fn steer(&mut self, change: fn(&mut Steering)) {
+        change(&mut self.steering);
+        self.drive(MotorCommand::Steer(self.steering));
+    }
+
+    // Driving by hand always takes over from any autonomous mode
+    fn drive(&mut self, command: MotorCommand) {
+        if !Mode::current().is_manual() {
+            Mode::set(Mode::Manual);
+        }
+        self.braking = false;
+        self.record(Action::Motor(command));
+        Self::send_now(command);
+    }
+}
 
 impl IrButtonHandler for IrRemoteController {
     fn on_ok(&mut self) {
-        let _ = MOTORS_CHANNEL.try_send(MotorCommand::Stop);
+        let stop = if self.braking {
+            StopMode::Coast
+        } else {
+            StopMode::BrakeThenCoast
+        };
+        self.steering = Steering::default();
+        self.drive(MotorCommand::Stop(stop));
         debug!("Ok button pressed");
     }
     fn on_left(&mut self) {
-        let _ = MOTORS_CHANNEL.try_send(MotorCommand::Left);
-        debug!("Left button pressed: activate left motor");
+        match self.mapping {
+            DriveMapping::Tank => self.drive(MotorCommand::Left),
+            DriveMapping::Car => self.steer(Steering::steer_left),
+        }
+        debug!("Left button pressed: turn left");
     }
     fn on_up(&mut self) {
-        let _ = MOTORS_CHANNEL.try_send(MotorCommand::Forward);
-        debug!("Up button pressed: activate forward motor");
+        match self.mapping {
+            DriveMapping::Tank => self.drive(MotorCommand::Forward),
+            DriveMapping::Car => self.steer(Steering::accelerate),
+        }
+        debug!("Up button pressed: go forward");
     }
     fn on_right(&mut self) {
-        let _ = MOTORS_CHANNEL.try_send(MotorCommand::Right);
-        debug!("Right button pressed: activate right motor");
+        match self.mapping {
+            DriveMapping::Tank => self.drive(MotorCommand::Right),
+            DriveMapping::Car => self.steer(Steering::steer_right),
+        }
+        debug!("Right button pressed: turn right");
     }
     fn on_down(&mut self) {
-        let _ = MOTORS_CHANNEL.try_send(MotorCommand::Backward);
-        debug!("Down button pressed: activate backward motor");
+        match self.mapping {
+            DriveMapping::Tank => self.drive(MotorCommand::Backward),
+            DriveMapping::Car => self.steer(Steering::decelerate),
+        }
+        debug!("Down button pressed: go backward");
     }
     fn on_num(&mut self, n: u8) {
-        debug!("Number button {} pressed", n);
+        match Mode::from_num(n) {
+            Some(mode) => {
+                self.select_mode(mode);
+                debug!("Number button {} pressed: mode selected", n);
+            }
+            None => debug!("Number button {} pressed", n),
+        }
     }
     fn on_star(&mut self) {
+        self.record(Action::BigLeds(BigLedCommand::Toggle));
         let _ = BIG_LEDS_CHANNEL.try_send(BigLedCommand::Toggle);
         debug!("Star button pressed");
     }
     fn on_hash(&mut self) {
-        debug!("Hash button pressed");
+        self.mapping = match self.mapping {
+            DriveMapping::Tank => DriveMapping::Car,
+            DriveMapping::Car => DriveMapping::Tank,
+        };
+        self.steering = Steering::default();
+        self.drive(MotorCommand::Stop(StopMode::BrakeThenCoast));
+        debug!("Hash button pressed: {} mapping", self.mapping);
     }
     fn on_unknown(&mut self, cmd: u8) {
         debug!("Unknown button: 0x{:02X}", cmd);
@@ -120,6 +318,15 @@ pub enum IrDecodeResult {
 }
 
 pub fn decode_nec(timings: &[u32]) -> IrDecodeResult {
+    // A held button repeats only the leader and a single burst, far short of a frame
+    if timings.len() >= 2
+        && timings[0] > NEC_LEADER_LOW_MIN
+        && timings[0] < NEC_LEADER_LOW_MAX
+        && timings[1] > NEC_REPEAT_HIGH_MIN
+        && timings[1] < NEC_REPEAT_HIGH_MAX
+    {
+        return IrDecodeResult::Repeat;
+    }
     if timings.len() < 2 + 2 * 32 {
         return IrDecodeResult::None;
     }
@@ -136,13 +343,82 @@ pub fn decode_nec(timings: &[u32]) -> IrDecodeResult {
         }
         let command = ((data >> 16) & 0xFF) as u8;
         IrDecodeResult::Button(IrButton::from_command(command))
-    } else if timings[0] > NEC_LEADER_LOW_MIN
-        && timings[0] < NEC_LEADER_LOW_MAX
-        && timings[1] > NEC_REPEAT_HIGH_MIN
-        && timings[1] < NEC_REPEAT_HIGH_MAX
-    {
-        IrDecodeResult::Repeat
     } else {
         IrDecodeResult::None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIT_LOW: u32 = 560;
+    const ZERO_HIGH: u32 = 560;
+    const ONE_HIGH: u32 = 1690;
+
+    // Leader, address, inverted address, command, inverted command and the final burst,
+    // least significant bit first like the remote sends them
+    fn frame(command: u8) -> Vec<u32> {
+        let data = u32::from_le_bytes([0x00, 0xFF, command, !command]);
+        let mut timings = vec![9000, 4500];
+        for bit in 0..32 {
+            let high = if data & (1 << bit) != 0 {
+                ONE_HIGH
+            } else {
+                ZERO_HIGH
+            };
+            timings.extend([BIT_LOW, high]);
+        }
+        timings.push(BIT_LOW);
+        timings
+    }
+
+    #[test]
+    fn decodes_a_full_frame() {
+        assert!(matches!(
+            decode_nec(&frame(0x40)),
+            IrDecodeResult::Button(IrButton::Ok)
+        ));
+        assert!(matches!(
+            decode_nec(&frame(0x18)),
+            IrDecodeResult::Button(IrButton::Num(5))
+        ));
+    }
+
+    #[test]
+    fn decodes_a_repeat_code() {
+        assert!(matches!(
+            decode_nec(&[9000, 2250, BIT_LOW]),
+            IrDecodeResult::Repeat
+        ));
+    }
+
+    // Tapping a key and holding it choose different modes
+    #[test]
+    fn held_keys_select_their_own_modes() {
+        for (command, tapped, held) in [
+            (0x52, Mode::Manual, Mode::ReturnToStart),
+            (0x18, Mode::WheelTrim, Mode::CompassCalibration),
+        ] {
+            let IrDecodeResult::Button(IrButton::Num(n)) = decode_nec(&frame(command)) else {
+                panic!("not a number key: 0x{command:02X}");
+            };
+            assert!(Mode::from_num(n) == Some(tapped));
+            assert!(Mode::from_held_num(n) == Some(held));
+        }
+    }
+
+    #[test]
+    fn ignores_noise_and_cut_frames() {
+        assert!(matches!(decode_nec(&[]), IrDecodeResult::None));
+        assert!(matches!(decode_nec(&[9000]), IrDecodeResult::None));
+        assert!(matches!(
+            decode_nec(&[600, 2250, BIT_LOW]),
+            IrDecodeResult::None
+        ));
+        assert!(matches!(
+            decode_nec(&frame(0x40)[..40]),
+            IrDecodeResult::None
+        ));
+    }
+}
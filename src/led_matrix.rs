@@ -0,0 +1,275 @@
+use crate::display::{BRIGHTNESS_BITS, DisplayCommand, Icon, Image, MAX_BRIGHTNESS};
+use embassy_nrf::{
+    Peri,
+    gpio::{AnyPin, Level, Output, OutputDrive},
+};
+use embassy_time::{Duration, Instant, Timer};
+
+// A row stays on for 1, 2 and 4 times this, one for each bit of the brightness.
+// All five rows take 7ms, fast enough not to flicker
+const BIT_TIME_US: u64 = 200;
+// Time for the text to move by one column
+const SCROLL_STEP: Duration = Duration::from_millis(120);
+// Blank column between two characters
+const CHARACTER_WIDTH: usize = 6;
+// Outlines, so what is lit inside them stands out
+const DIM: u8 = 2;
+
+impl Image {
+    // Bit 4 of every row is the left column
+    const fn from_rows(rows: [u8; 5], brightness: u8) -> Self {
+        let mut pixels = [[0; 5]; 5];
+        let mut row = 0;
+        while row < 5 {
+            let mut column = 0;
+            while column < 5 {
+                if rows[row] & (0x10 >> column) != 0 {
+                    pixels[row][column] = brightness;
+                }
+                column += 1;
+            }
+            row += 1;
+        }
+        Self { pixels }
+    }
+}
+
+impl Icon {
+    const fn image(self) -> Image {
+        let rows = match self {
+            Icon::ArrowUp => [0b00100, 0b01110, 0b10101, 0b00100, 0b00100],
+            Icon::ArrowDown => [0b00100, 0b00100, 0b10101, 0b01110, 0b00100],
+            Icon::ArrowLeft => [0b00100, 0b01000, 0b11111, 0b01000, 0b00100],
+            Icon::ArrowRight => [0b00100, 0b00010, 0b11111, 0b00010, 0b00100],
+            Icon::Stop => [0b01110, 0b11111, 0b11111, 0b11111, 0b01110],
+            Icon::Tick => [0b00000, 0b00001, 0b00010, 0b10100, 0b01000],
+            Icon::Cross => [0b10001, 0b01010, 0b00100, 0b01010, 0b10001],
+            Icon::Battery => {
+                // Dim outline, with the last bit of charge at the bottom
+                let mut image =
+                    Image::from_rows([0b00100, 0b01110, 0b01010, 0b01010, 0b01110], DIM);
+                image.pixels[3][2] = MAX_BRIGHTNESS;
+                return image;
+            }
+        };
+        Image::from_rows(rows, MAX_BRIGHTNESS)
+    }
+}
+
+// 5×5 glyphs in the same layout as Image::from_rows, anything missing shows as '?'
+fn glyph(character: char) -> [u8; 5] {
+    match character.to_ascii_uppercase() {
+        '0' => [0b01110, 0b10011, 0b10101, 0b11001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b11100, 0b00010, 0b01100, 0b10000, 0b11110],
+        '3' => [0b11110, 0b00010, 0b00100, 0b10010, 0b01100],
+        '4' => [0b00110, 0b01010, 0b10010, 0b11111, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b11110],
+        '6' => [0b00010, 0b00100, 0b01110, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00010, 0b00100, 0b01000, 0b10000],
+        '8' => [0b01110, 0b10001, 0b01110, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b01110, 0b00100, 0b01000],
+        'A' => [0b01100, 0b10010, 0b11110, 0b10010, 0b10010],
+        'B' => [0b11100, 0b10010, 0b11100, 0b10010, 0b11100],
+        'C' => [0b01110, 0b10000, 0b10000, 0b10000, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10010, 0b10010, 0b11100],
+        'E' => [0b11110, 0b10000, 0b11100, 0b10000, 0b11110],
+        'F' => [0b11110, 0b10000, 0b11100, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10000, 0b10011, 0b10001, 0b01110],
+        'H' => [0b10010, 0b10010, 0b11110, 0b10010, 0b10010],
+        'I' => [0b11100, 0b01000, 0b01000, 0b01000, 0b11100],
+        'J' => [0b11110, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10010, 0b10100, 0b11000, 0b10100, 0b10010],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b11110],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10001, 0b10001],
+        'N' => [0b10001, 0b11001, 0b10101, 0b10011, 0b10001],
+        'O' => [0b01100, 0b10010, 0b10010, 0b10010, 0b01100],
+        'P' => [0b11100, 0b10010, 0b11100, 0b10000, 0b10000],
+        'Q' => [0b01100, 0b10010, 0b10010, 0b01100, 0b00110],
+        'R' => [0b11100, 0b10010, 0b11100, 0b10100, 0b10010],
+        'S' => [0b01110, 0b10000, 0b01100, 0b00010, 0b11100],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10010, 0b10010, 0b10010, 0b10010, 0b01100],
+        'V' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10101, 0b11011, 0b10001],
+        'X' => [0b10010, 0b10010, 0b01100, 0b10010, 0b10010],
+        'Y' => [0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11110, 0b00100, 0b01000, 0b10000, 0b11110],
+        ' ' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000],
+        '!' => [0b00100, 0b00100, 0b00100, 0b00000, 0b00100],
+        '-' => [0b00000, 0b00000, 0b01110, 0b00000, 0b00000],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00100],
+        ':' => [0b00000, 0b00100, 0b00000, 0b00100, 0b00000],
+        _ => [0b01110, 0b10001, 0b00110, 0b00000, 0b00100],
+    }
+}
+
+// What the display shows between two commands
+pub enum Content {
+    Still(Image),
+    Scroll { text: &'static str, since: Instant },
+}
+
+impl Content {
+    pub fn new(command: DisplayCommand, now: Instant) -> Self {
+        match command {
+            DisplayCommand::Clear => Content::Still(Image::BLANK),
+            DisplayCommand::Image(image) => Content::Still(image),
+            DisplayCommand::Icon(icon) => Content::Still(icon.image()),
+            DisplayCommand::Text(text) => match text.chars().count() {
+                0 => Content::Still(Image::BLANK),
+                1 => Content::Still(Image::from_rows(
+                    glyph(text.chars().next().unwrap_or(' ')),
+                    MAX_BRIGHTNESS,
+                )),
+                _ => Content::Scroll { text, since: now },
+            },
+        }
+    }
+
+    // The text comes in from the right and goes out on the left, then the display is blank
+    pub fn frame(&self, now: Instant) -> Image {
+        let (text, since) = match self {
+            Content::Still(image) => return *image,
+            Content::Scroll { text, since } => (text, since),
+        };
+        let offset = ((now - *since).as_millis() / SCROLL_STEP.as_millis()) as usize;
+        let mut image = Image::BLANK;
+        for x in 0..5 {
+            // The text starts just off the right edge
+            let Some(column) = (offset + x).checked_sub(5) else {
+                continue;
+            };
+            let Some(character) = text.chars().nth(column / CHARACTER_WIDTH) else {
+                continue;
+            };
+            let column_in_glyph = column % CHARACTER_WIDTH;
+            if column_in_glyph >= 5 {
+                continue;
+            }
+            let rows = glyph(character).map(|row| (row << column_in_glyph >> x) & (0x10 >> x));
+            overlay(&mut image, &Image::from_rows(rows, MAX_BRIGHTNESS));
+        }
+        image
+    }
+}
+
+// Lights every pixel lit in either image
+fn overlay(image: &mut Image, other: &Image) {
+    for (pixel, other) in image
+        .pixels
+        .as_flattened_mut()
+        .iter_mut()
+        .zip(other.pixels.as_flattened())
+    {
+        *pixel = (*pixel).max(*other);
+    }
+}
+
+// The LED matrix of the micro:bit
+pub struct Display<'d> {
+    // High lights the row
+    rows: [Output<'d>; 5],
+    // Low lights the pixel of the column in the lit row
+    columns: [Output<'d>; 5],
+}
+
+impl<'d> Display<'d> {
+    pub fn new(rows: [Peri<'d, AnyPin>; 5], columns: [Peri<'d, AnyPin>; 5]) -> Self {
+        Self {
+            rows: rows.map(|pin| Output::new(pin, Level::Low, OutputDrive::Standard)),
+            columns: columns.map(|pin| Output::new(pin, Level::High, OutputDrive::Standard)),
+        }
+    }
+
+    // Lights every row once, each bit of the brightness for its own share of the time
+    pub async fn refresh(&mut self, image: &Image) {
+        for (row, row_pin) in image.pixels.iter().zip(&mut self.rows) {
+            row_pin.set_high();
+            for bit in 0..BRIGHTNESS_BITS {
+                for (brightness, column) in row.iter().zip(&mut self.columns) {
+                    if brightness >> bit & 1 != 0 {
+                        column.set_low();
+                    } else {
+                        column.set_high();
+                    }
+                }
+                Timer::after_micros(BIT_TIME_US << bit).await;
+            }
+            row_pin.set_low();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scroll(text: &'static str) -> Content {
+        Content::new(DisplayCommand::Text(text), Instant::from_millis(0))
+    }
+
+    // Halfway through the step, the ticks of the clock don't add up to whole milliseconds
+    fn after_steps(steps: u64) -> Instant {
+        Instant::from_millis(steps * SCROLL_STEP.as_millis() + SCROLL_STEP.as_millis() / 2)
+    }
+
+    fn character(character: char) -> Image {
+        Image::from_rows(glyph(character), MAX_BRIGHTNESS)
+    }
+
+    #[test]
+    fn single_characters_and_icons_stand_still() {
+        let content = Content::new(DisplayCommand::Text("7"), Instant::from_millis(0));
+        assert_eq!(
+            content.frame(after_steps(100)).pixels,
+            character('7').pixels
+        );
+        let content = Content::new(DisplayCommand::Icon(Icon::Tick), Instant::from_millis(0));
+        assert_eq!(
+            content.frame(after_steps(100)).pixels,
+            Icon::Tick.image().pixels
+        );
+    }
+
+    #[test]
+    fn text_comes_in_from_the_right() {
+        let content = scroll("AB");
+        assert_eq!(content.frame(after_steps(0)).pixels, Image::BLANK.pixels);
+
+        // One step in, the left column of the first character is on the right edge
+        let mut expected = Image::BLANK;
+        for (row, pixels) in expected.pixels.iter_mut().enumerate() {
+            pixels[4] = character('A').pixels[row][0];
+        }
+        assert_eq!(content.frame(after_steps(1)).pixels, expected.pixels);
+    }
+
+    #[test]
+    fn every_character_gets_the_whole_display_in_turn() {
+        let content = scroll("AB");
+        assert_eq!(content.frame(after_steps(5)).pixels, character('A').pixels);
+        assert_eq!(
+            content
+                .frame(after_steps(5 + CHARACTER_WIDTH as u64))
+                .pixels,
+            character('B').pixels
+        );
+    }
+
+    #[test]
+    fn text_goes_out_on_the_left_and_leaves_the_display_blank() {
+        // M reaches to its last column, which is on the left edge here
+        let content = scroll("AM");
+        let last_step = 5 + 2 * CHARACTER_WIDTH as u64 - 2;
+        assert_ne!(
+            content.frame(after_steps(last_step)).pixels,
+            Image::BLANK.pixels
+        );
+        assert_eq!(
+            content.frame(after_steps(last_step + 1)).pixels,
+            Image::BLANK.pixels
+        );
+        assert_eq!(content.frame(after_steps(1000)).pixels, Image::BLANK.pixels);
+    }
+}
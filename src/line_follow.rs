@@ -0,0 +1,322 @@
+use crate::{
+    line_sensor::{LINE_POSITION_MAX, LineState},
+    motor::Motion,
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+
+// Tuning from the remote while in Mode::LineFollow, picked up on the next step
+pub static LINE_FOLLOW_TUNING: Channel<ThreadModeRawMutex, LineFollowTuning, 2> = Channel::new();
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum LineFollowTuning {
+    // The next stiffer of PidGains::PRESETS, back to the gentlest after the stiffest
+    NextGains,
+    // Between turning and strafing towards the line
+    ToggleLateralCorrection,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+impl PidGains {
+    // From gentle to sharp, the middle one suits the usual tape track
+    pub const PRESETS: [Self; 3] = [
+        Self {
+            kp: 110.0,
+            ki: 0.0,
+            kd: 8.0,
+        },
+        Self {
+            kp: 160.0,
+            ki: 0.0,
+            kd: 12.0,
+        },
+        Self {
+            kp: 220.0,
+            ki: 0.0,
+            kd: 18.0,
+        },
+    ];
+}
+
+pub struct Pid {
+    gains: PidGains,
+    output_limit: f32,
+    integral: f32,
+    previous_error: Option<f32>,
+}
+
+impl Pid {
+    pub const fn new(gains: PidGains, output_limit: f32) -> Self {
+        Self {
+            gains,
+            output_limit,
+            integral: 0.0,
+            previous_error: None,
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: PidGains) {
+        self.gains = gains;
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous_error = None;
+    }
+
+    // dt in seconds
+    pub fn update(&mut self, error: f32, dt: f32) -> f32 {
+        let derivative = match self.previous_error {
+            Some(previous_error) if dt > 0.0 => (error - previous_error) / dt,
+            _ => 0.0,
+        };
+        self.previous_error = Some(error);
+
+        let integral = self.integral + error * dt;
+        let PidGains { kp, ki, kd } = self.gains;
+        let output = kp * error + ki * integral + kd * derivative;
+        // Anti windup, stop integrating while the output is saturated
+        if output.abs() < self.output_limit {
+            self.integral = integral;
+        }
+        output.clamp(-self.output_limit, self.output_limit)
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct LineFollowConfig {
+    pub gains: PidGains,
+    pub base_speed: i16,
+    // Mecanum wheels can correct sideways instead of turning
+    pub lateral_correction: bool,
+    pub search_speed: i16,
+    // Length of the first sweep towards the side the line was last seen
+    pub first_sweep: Duration,
+    pub search_timeout: Duration,
+}
+
+impl LineFollowConfig {
+    pub const DEFAULT: Self = Self {
+        gains: PidGains::PRESETS[1],
+        base_speed: 0xA0,
+        lateral_correction: false,
+        search_speed: 0x90,
+        first_sweep: Duration::from_millis(300),
+        search_timeout: Duration::from_secs(4),
+    };
+}
+
+pub enum FollowStep {
+    Drive(Motion),
+    // The search timed out, time to give up
+    Lost,
+}
+
+enum FollowState {
+    Following,
+    Searching {
+        started: Instant,
+        sweep_started: Instant,
+        sweep: Duration,
+        turn: i16,
+    },
+}
+
+pub struct LineFollower {
+    config: LineFollowConfig,
+    pid: Pid,
+    state: FollowState,
+    last_error: f32,
+    last_step: Option<Instant>,
+}
+
+impl LineFollower {
+    pub const fn new(config: LineFollowConfig) -> Self {
+        Self {
+            config,
+            pid: Pid::new(config.gains, 0xFF as f32),
+            state: FollowState::Following,
+            last_error: 0.0,
+            last_step: None,
+        }
+    }
+
+    pub fn config(&self) -> &LineFollowConfig {
+        &self.config
+    }
+
+    pub fn tune(&mut self, tuning: LineFollowTuning) {
+        match tuning {
+            LineFollowTuning::NextGains => {
+                let gains = PidGains::PRESETS
+                    .into_iter()
+                    .find(|gains| gains.kp > self.config.gains.kp)
+                    .unwrap_or(PidGains::PRESETS[0]);
+                self.config.gains = gains;
+                self.pid.set_gains(gains);
+            }
+            LineFollowTuning::ToggleLateralCorrection => {
+                self.config.lateral_correction = !self.config.lateral_correction;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pid.reset();
+        self.state = FollowState::Following;
+        self.last_error = 0.0;
+        self.last_step = None;
+    }
+
+    pub fn step(&mut self, line: &LineState, now: Instant) -> FollowStep {
+        let dt = self
+            .last_step
+            .map(|last_step| (now - last_step).as_micros() as f32 / 1_000_000.0)
+            .unwrap_or(0.0);
+        self.last_step = Some(now);
+
+        let Some(position) = line.position else {
+            return self.search(now);
+        };
+        self.state = FollowState::Following;
+
+        // Positive error means the line is to the right, so turn clockwise
+        let error = position as f32 / LINE_POSITION_MAX as f32;
+        self.last_error = error;
+        let correction = self.pid.update(error, dt) as i16;
+        // Slow down while correcting hard, so the car doesn't overshoot the corner
+        let forward = self.config.base_speed - correction.abs() / 4;
+
+        FollowStep::Drive(if self.config.lateral_correction {
+            Motion {
+                forward,
+                strafe: correction,
+                turn: 0,
+            }
+        } else {
+            Motion {
+                forward,
+                strafe: 0,
+                turn: correction,
+            }
+        })
+    }
+
+    // Sweep left and right around the last known position, wider every time
+    fn search(&mut self, now: Instant) -> FollowStep {
+        let FollowState::Searching {
+            started,
+            sweep_started,
+            sweep,
+            turn,
+        } = &mut self.state
+        else {
+            // Just lost, the first sweep goes towards where the line was last seen
+            self.pid.reset();
+            let turn = if self.last_error < 0.0 {
+                -self.config.search_speed
+            } else {
+                self.config.search_speed
+            };
+            self.state = FollowState::Searching {
+                started: now,
+                sweep_started: now,
+                sweep: self.config.first_sweep,
+                turn,
+            };
+            return FollowStep::Drive(Motion {
+                forward: 0,
+                strafe: 0,
+                turn,
+            });
+        };
+        if now - *started > self.config.search_timeout {
+            return FollowStep::Lost;
+        }
+        if now - *sweep_started > *sweep {
+            // Going back has to undo the previous sweep before covering new ground
+            *sweep_started = now;
+            *sweep *= 2;
+            *turn = -*turn;
+        }
+
+        FollowStep::Drive(Motion {
+            forward: 0,
+            strafe: 0,
+            turn: *turn,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line_sensor::LINE_SENSOR_COUNT;
+
+    fn line_at(position: Option<i16>) -> LineState {
+        LineState {
+            raw: [0; LINE_SENSOR_COUNT],
+            mask: 0,
+            position,
+        }
+    }
+
+    fn motion(step: FollowStep) -> Motion {
+        match step {
+            FollowStep::Drive(motion) => motion,
+            FollowStep::Lost => panic!("gave up"),
+        }
+    }
+
+    #[test]
+    fn turns_or_strafes_towards_the_line() {
+        let mut follower = LineFollower::new(LineFollowConfig::DEFAULT);
+        let now = Instant::from_millis(0);
+        let turning = motion(follower.step(&line_at(Some(500)), now));
+        assert!(turning.turn > 0 && turning.strafe == 0);
+
+        follower.tune(LineFollowTuning::ToggleLateralCorrection);
+        follower.reset();
+        let strafing = motion(follower.step(&line_at(Some(500)), now));
+        assert!(strafing.strafe > 0 && strafing.turn == 0);
+    }
+
+    #[test]
+    fn gains_go_round_the_presets() {
+        let mut follower = LineFollower::new(LineFollowConfig::DEFAULT);
+        let mut seen = [0.0; 4];
+        for kp in &mut seen {
+            follower.tune(LineFollowTuning::NextGains);
+            *kp = follower.config().gains.kp;
+        }
+        let presets = PidGains::PRESETS.map(|gains| gains.kp);
+        assert_eq!(seen, [presets[2], presets[0], presets[1], presets[2]]);
+    }
+
+    #[test]
+    fn searches_where_the_line_was_last_seen_then_gives_up() {
+        let config = LineFollowConfig::DEFAULT;
+        let mut follower = LineFollower::new(config);
+        follower.step(&line_at(Some(-400)), Instant::from_millis(0));
+
+        let first = motion(follower.step(&line_at(None), Instant::from_millis(20)));
+        assert_eq!(first.turn, -config.search_speed);
+        let after_first_sweep = Instant::from_millis(20) + config.first_sweep;
+        let back =
+            motion(follower.step(&line_at(None), after_first_sweep + Duration::from_millis(1)));
+        assert_eq!(back.turn, config.search_speed);
+
+        let too_late = Instant::from_millis(20) + config.search_timeout + Duration::from_millis(1);
+        assert!(matches!(
+            follower.step(&line_at(None), too_late),
+            FollowStep::Lost
+        ));
+    }
+}
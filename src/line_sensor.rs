@@ -0,0 +1,147 @@
+use crate::storage::{Record, Slot};
+#[cfg(feature = "line-sensor-saadc")]
+use embassy_nrf::{bind_interrupts, saadc};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, watch::Watch};
+
+#[cfg(feature = "line-sensor-saadc")]
+bind_interrupts!(pub struct Irqs {
+    SAADC => saadc::InterruptHandler;
+});
+
+// Left, center and right sensors, in that order
+pub const LINE_SENSOR_COUNT: usize = 3;
+// Full scale of a raw reading. Digital sensors read either 0 or this value
+pub const LINE_SENSOR_MAX: u16 = 4095;
+// Position reported when only the leftmost/rightmost sensor sees the line
+pub const LINE_POSITION_MAX: i16 = 1000;
+// Smallest difference between line and floor for a calibration to be trusted
+const LINE_MIN_CONTRAST: u16 = LINE_SENSOR_MAX / 8;
+
+// Latest sensor state, for the line follower and anyone else interested
+pub static LINE_STATE: Watch<ThreadModeRawMutex, LineState, 4> = Watch::new();
+// Calibration in use by the line sensor task, send a new one to replace it
+pub static LINE_CALIBRATION: Watch<ThreadModeRawMutex, LineCalibration, 2> =
+    Watch::new_with(LineCalibration::DEFAULT);
+
+#[derive(Clone, Copy)]
+pub struct LineCalibration {
+    // A sensor sees the line when its (possibly inverted) reading is above this
+    pub thresholds: [u16; LINE_SENSOR_COUNT],
+    // Sensors that read high over white instead of over black
+    pub inverted: [bool; LINE_SENSOR_COUNT],
+}
+
+impl LineCalibration {
+    pub const DEFAULT: Self = Self {
+        thresholds: [LINE_SENSOR_MAX / 2; LINE_SENSOR_COUNT],
+        inverted: [false; LINE_SENSOR_COUNT],
+    };
+
+    // Raw reading as seen by a non inverted sensor, high means dark
+    pub fn normalize(&self, sensor: usize, raw: u16) -> u16 {
+        if self.inverted[sensor] {
+            LINE_SENSOR_MAX - raw.min(LINE_SENSOR_MAX)
+        } else {
+            raw.min(LINE_SENSOR_MAX)
+        }
+    }
+
+    pub fn state(&self, raw: [u16; LINE_SENSOR_COUNT]) -> LineState {
+        let mut mask = 0u8;
+        for (i, &value) in raw.iter().enumerate() {
+            if self.normalize(i, value) > self.thresholds[i] {
+                mask |= 1 << i;
+            }
+        }
+        LineState {
+            raw,
+            mask,
+            position: LineState::position_from_mask(mask),
+        }
+    }
+}
+
+impl Record for LineCalibration {
+    const SLOT: Slot = Slot::LineCalibration;
+    const SIZE: usize = LINE_SENSOR_COUNT * 3;
+
+    fn encode(&self, buf: &mut [u8]) {
+        for (i, chunk) in buf.as_chunks_mut::<3>().0.iter_mut().enumerate() {
+            chunk[..2].copy_from_slice(&self.thresholds[i].to_le_bytes());
+            chunk[2] = self.inverted[i] as u8;
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut calibration = Self::DEFAULT;
+        for (i, chunk) in buf.as_chunks::<3>().0.iter().enumerate() {
+            calibration.thresholds[i] = u16::from_le_bytes([chunk[0], chunk[1]]);
+            calibration.inverted[i] = chunk[2] != 0;
+        }
+        Some(calibration)
+    }
+}
+
+// Collects the darkest and brightest readings of each sensor while sweeping over the line
+pub struct LineCalibrator {
+    base: LineCalibration,
+    min: [u16; LINE_SENSOR_COUNT],
+    max: [u16; LINE_SENSOR_COUNT],
+}
+
+impl LineCalibrator {
+    // Inversion can't be guessed from the readings, so it is kept from `base`
+    pub fn new(base: LineCalibration) -> Self {
+        Self {
+            base,
+            min: [LINE_SENSOR_MAX; LINE_SENSOR_COUNT],
+            max: [0; LINE_SENSOR_COUNT],
+        }
+    }
+
+    pub fn sample(&mut self, raw: &[u16; LINE_SENSOR_COUNT]) {
+        for (i, &value) in raw.iter().enumerate() {
+            let value = self.base.normalize(i, value);
+            self.min[i] = self.min[i].min(value);
+            self.max[i] = self.max[i].max(value);
+        }
+    }
+
+    // None if some sensor never saw both the line and the floor
+    pub fn finish(&self) -> Option<LineCalibration> {
+        let mut calibration = self.base;
+        for i in 0..LINE_SENSOR_COUNT {
+            if self.max[i] < self.min[i] + LINE_MIN_CONTRAST {
+                return None;
+            }
+            calibration.thresholds[i] = self.min[i] + (self.max[i] - self.min[i]) / 2;
+        }
+        Some(calibration)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LineState {
+    // Readings straight from the sensors, before inversion
+    pub raw: [u16; LINE_SENSOR_COUNT],
+    // Bit 0 is the left sensor, set when it sees the line
+    pub mask: u8,
+    // -1000 (line under the left sensor) to 1000 (under the right one), None if lost
+    pub position: Option<i16>,
+}
+
+impl LineState {
+    fn position_from_mask(mask: u8) -> Option<i16> {
+        if mask == 0 {
+            return None;
+        }
+        // Average of the positions of every sensor on the line
+        let step = 2 * LINE_POSITION_MAX as i32 / (LINE_SENSOR_COUNT as i32 - 1);
+        let (sum, count) = (0..LINE_SENSOR_COUNT)
+            .filter(|i| mask & (1 << i) != 0)
+            .fold((0i32, 0i32), |(sum, count), i| {
+                (sum + i as i32 * step - LINE_POSITION_MAX as i32, count + 1)
+            });
+        Some((sum / count) as i16)
+    }
+}
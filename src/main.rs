@@ -1,18 +1,47 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
+use compass::{COMPASS_CALIBRATION, CompassCalibration};
 use defmt::info;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
+use line_sensor::{LINE_CALIBRATION, LineCalibration};
+use motor::{WHEEL_CALIBRATION, WheelCalibration};
+#[cfg(not(test))]
 use panic_probe as _;
 
+// The unit tests run on the host, where std panics for defmt
+#[cfg(test)]
+#[defmt::panic_handler]
+fn panic() -> ! {
+    panic!()
+}
+
+#[cfg(all(feature = "full-matrix", feature = "line-sensor-saadc"))]
+compile_error!("The LED matrix needs P0_31, which the analog line sensors use");
+
 mod tasks;
 use tasks::*;
 mod big_led;
 mod bottom_led;
+mod compass;
+mod display;
+mod expander;
 mod ir_remote_control;
+#[cfg(feature = "full-matrix")]
+mod led_matrix;
+mod line_follow;
+mod line_sensor;
+mod math;
+mod maze;
+mod mode;
+mod motion_sensor;
 mod motor;
+mod odometry;
+mod recorder;
+mod safety;
 mod servo;
+mod storage;
 mod twim;
 
 #[embassy_executor::main]
@@ -20,14 +49,28 @@ async fn main(spawner: Spawner) {
     info!("Starting...");
     let p = embassy_nrf::init(Default::default());
 
+    // Settings saved in flash, like the sensor calibrations
+    storage::init(p.NVMC);
+    if let Some(calibration) = storage::load::<LineCalibration>() {
+        LINE_CALIBRATION.sender().send(calibration);
+    }
+    if let Some(calibration) = storage::load::<WheelCalibration>() {
+        WHEEL_CALIBRATION.sender().send(calibration);
+    }
+    if let Some(calibration) = storage::load::<CompassCalibration>() {
+        COMPASS_CALIBRATION.sender().send(calibration);
+    }
+
     // Communication for Big Leds and Motors
     spawner.must_spawn(twin_task(p.TWISPI0, p.P1_00, p.P0_26));
+    spawner.must_spawn(twin_stats());
 
     // Two Big Leds in the front
     spawner.must_spawn(big_leds());
 
     // Four small ws2812B LEDs at the bottom
     // TODO Finish it, you lazy!
+    #[cfg(not(feature = "full-matrix"))]
     spawner.must_spawn(bottom_leds(p.PWM0, p.P0_11));
 
     // Servo for the head of the car
@@ -39,7 +82,65 @@ async fn main(spawner: Spawner) {
     // Infrared remote controller
     spawner.must_spawn(ir_remote_control(p.P0_02));
 
-    // TODO Line tracking sensor
+    // Line tracking sensors at the bottom
+    #[cfg(not(feature = "line-sensor-saadc"))]
+    spawner.must_spawn(line_sensors(p.P0_03, p.P0_04, p.P0_10));
+    #[cfg(feature = "line-sensor-saadc")]
+    spawner.must_spawn(line_sensors(p.SAADC, p.P0_03, p.P0_04, p.P0_31));
+
+    // Follows the line when selected from the remote
+    spawner.must_spawn(line_follower());
+    spawner.must_spawn(line_calibration());
+    spawner.must_spawn(maze_solver());
+
+    // Per wheel calibration, edited from the remote
+    spawner.must_spawn(wheel_trim());
+
+    // Records driving sessions from the remote and plays them back
+    spawner.must_spawn(recorder());
+
+    // Drives back to the start from the dead-reckoned pose
+    spawner.must_spawn(return_to_start());
+
+    // Stops the car before it drives off the table, in every mode
+    spawner.must_spawn(cliff_guard());
+
+    // Accelerometer and magnetometer of the micro:bit, on its internal I2C bus
+    spawner.must_spawn(motion_sensor(p.TWISPI1, p.P0_16, p.P0_08, p.P0_25));
+
+    // Heading of the car from the magnetometer, tilt compensated
+    spawner.must_spawn(compass());
+    spawner.must_spawn(compass_calibration());
+
+    // Stops the car when it gets hit, lifted or tipped over, until Ok is pressed
+    spawner.must_spawn(crash_guard());
+
+    // Stops the motors when they are driven but the car doesn't move
+    spawner.must_spawn(stall_guard());
+
+    // 5x5 LED matrix on the front of the micro:bit, showing the mode and what the car does.
+    // Two of its columns are the data line of the bottom LEDs and an analog line sensor,
+    // so it only lights up when built with the full-matrix feature
+    #[cfg(feature = "full-matrix")]
+    spawner.must_spawn(display(
+        [
+            p.P0_21.into(),
+            p.P0_22.into(),
+            p.P0_15.into(),
+            p.P0_24.into(),
+            p.P0_19.into(),
+        ],
+        [
+            p.P0_28.into(),
+            p.P0_11.into(),
+            p.P0_31.into(),
+            p.P1_05.into(),
+            p.P0_30.into(),
+        ],
+    ));
+    #[cfg(not(feature = "full-matrix"))]
+    spawner.must_spawn(display());
+    spawner.must_spawn(dashboard());
 
     // TODO Ultrasonic sensor
 
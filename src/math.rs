@@ -0,0 +1,68 @@
+// The float functions of std aren't in core, these are close enough for driving around
+use core::f32::consts::{FRAC_PI_2, PI};
+
+// Into -PI..=PI
+pub fn wrap_angle(angle: f32) -> f32 {
+    let mut angle = angle % (2.0 * PI);
+    if angle > PI {
+        angle -= 2.0 * PI;
+    } else if angle < -PI {
+        angle += 2.0 * PI;
+    }
+    angle
+}
+
+// Taylor series folded onto -PI/2..=PI/2, better than 0.001 everywhere
+pub fn sin(angle: f32) -> f32 {
+    let mut x = wrap_angle(angle);
+    if x > FRAC_PI_2 {
+        x = PI - x;
+    } else if x < -FRAC_PI_2 {
+        x = -PI - x;
+    }
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0)))
+}
+
+pub fn cos(angle: f32) -> f32 {
+    sin(angle + FRAC_PI_2)
+}
+
+// Within 0.005 rad
+pub fn atan2(y: f32, x: f32) -> f32 {
+    if x == 0.0 && y == 0.0 {
+        return 0.0;
+    }
+    let (small, large) = if x.abs() > y.abs() { (y, x) } else { (x, y) };
+    let ratio = small / large;
+    let atan = ratio * (FRAC_PI_2 / 2.0 + 0.273 * (1.0 - ratio.abs()));
+    if x.abs() > y.abs() {
+        if x > 0.0 {
+            atan
+        } else if y >= 0.0 {
+            atan + PI
+        } else {
+            atan - PI
+        }
+    } else if y > 0.0 {
+        FRAC_PI_2 - atan
+    } else {
+        -FRAC_PI_2 - atan
+    }
+}
+
+pub fn sqrt(value: f32) -> f32 {
+    if value <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent is a good first guess, Newton does the rest
+    let mut root = f32::from_bits((value.to_bits() >> 1) + 0x1FC0_0000);
+    for _ in 0..3 {
+        root = 0.5 * (root + value / root);
+    }
+    root
+}
+
+pub fn hypot(x: f32, y: f32) -> f32 {
+    sqrt(x * x + y * y)
+}
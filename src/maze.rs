@@ -0,0 +1,315 @@
+use crate::line_sensor::LineState;
+
+// Left, center and right sensor bits of LineState::mask
+const LEFT: u8 = 0b001;
+const CENTER: u8 = 0b010;
+const RIGHT: u8 = 0b100;
+// Consecutive samples needed before believing a branch or the end of the line
+const CONFIRM_SAMPLES: u8 = 3;
+pub const MAX_ROUTE_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Turn {
+    Left,
+    Straight,
+    Right,
+    Back,
+}
+
+impl Turn {
+    // Clockwise angle of the turn, in quarter turns
+    const fn quarters(self) -> u8 {
+        match self {
+            Turn::Straight => 0,
+            Turn::Right => 1,
+            Turn::Back => 2,
+            Turn::Left => 3,
+        }
+    }
+
+    const fn from_quarters(quarters: u8) -> Self {
+        match quarters % 4 {
+            0 => Turn::Straight,
+            1 => Turn::Right,
+            2 => Turn::Back,
+            _ => Turn::Left,
+        }
+    }
+}
+
+// Exits found at a junction, besides the way back
+#[derive(Clone, Copy, Default)]
+pub struct Junction {
+    pub left: bool,
+    pub straight: bool,
+    pub right: bool,
+}
+
+impl Junction {
+    // Left hand rule: keep the left hand on the wall
+    pub fn left_hand_turn(&self) -> Turn {
+        if self.left {
+            Turn::Left
+        } else if self.straight {
+            Turn::Straight
+        } else if self.right {
+            Turn::Right
+        } else {
+            Turn::Back
+        }
+    }
+
+    pub fn is_dead_end(&self) -> bool {
+        !(self.left || self.straight || self.right)
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum MazeError {
+    RouteFull,
+    // Spinning never found a line to stop on
+    TurnTimeout,
+    // The speed run met something that isn't on the recorded route
+    OffRoute,
+}
+
+pub enum MazeEvent {
+    // A branch starts under the car, drive over it to see what's ahead
+    Branch { left: bool, right: bool },
+    // The line ended without any branch
+    DeadEnd,
+}
+
+// Watches the sensor pattern while following the line between junctions
+#[derive(Default)]
+pub struct JunctionDetector {
+    branch_samples: u8,
+    lost_samples: u8,
+    left: bool,
+    right: bool,
+}
+
+impl JunctionDetector {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn update(&mut self, line: &LineState) -> Option<MazeEvent> {
+        let mask = line.mask;
+        if mask == 0 {
+            self.branch_samples = 0;
+            self.lost_samples += 1;
+            if self.lost_samples >= CONFIRM_SAMPLES {
+                self.reset();
+                return Some(MazeEvent::DeadEnd);
+            }
+            return None;
+        }
+        self.lost_samples = 0;
+
+        // An outer sensor together with the center one means a line going sideways
+        let left = mask & (LEFT | CENTER) == LEFT | CENTER;
+        let right = mask & (RIGHT | CENTER) == RIGHT | CENTER;
+        if !(left || right) {
+            self.branch_samples = 0;
+            self.left = false;
+            self.right = false;
+            return None;
+        }
+        self.left |= left;
+        self.right |= right;
+        self.branch_samples += 1;
+        if self.branch_samples >= CONFIRM_SAMPLES {
+            let event = MazeEvent::Branch {
+                left: self.left,
+                right: self.right,
+            };
+            self.reset();
+            return Some(event);
+        }
+        None
+    }
+
+    // A branch may be starting under the car, it isn't confirmed yet
+    pub fn over_branch(&self) -> bool {
+        self.branch_samples > 0
+    }
+
+    // Keep collecting the sides while driving over a branch, it may be wider on one side
+    pub fn sides(line: &LineState) -> (bool, bool) {
+        (line.mask & LEFT != 0, line.mask & RIGHT != 0)
+    }
+
+    pub fn straight(line: &LineState) -> bool {
+        line.mask & CENTER != 0
+    }
+
+    // Every sensor still dark after crossing a branch: this is the finish pad
+    pub fn at_goal(line: &LineState) -> bool {
+        line.mask == LEFT | CENTER | RIGHT
+    }
+}
+
+// Turns taken at every junction, with the dead ends taken out as they are found
+#[derive(Clone, Copy)]
+pub struct MazeRoute {
+    turns: [Turn; MAX_ROUTE_LENGTH],
+    len: usize,
+}
+
+impl MazeRoute {
+    pub const fn new() -> Self {
+        Self {
+            turns: [Turn::Straight; MAX_ROUTE_LENGTH],
+            len: 0,
+        }
+    }
+
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // False if the route is full
+    pub fn push(&mut self, turn: Turn) -> bool {
+        if self.len == MAX_ROUTE_LENGTH {
+            return false;
+        }
+        self.turns[self.len] = turn;
+        self.len += 1;
+        self.simplify();
+        true
+    }
+
+    // "A, Back, B" means A led to a dead end, so replace it by the single turn
+    // that goes from A's entrance to B's exit, e.g. Left Back Right is just Back
+    fn simplify(&mut self) {
+        while self.len >= 3 && self.turns[self.len - 2] == Turn::Back {
+            let quarters = self.turns[self.len - 3..self.len]
+                .iter()
+                .map(|turn| turn.quarters())
+                .sum();
+            self.len -= 2;
+            self.turns[self.len - 1] = Turn::from_quarters(quarters);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line_sensor::LINE_SENSOR_COUNT;
+
+    fn line(mask: u8) -> LineState {
+        LineState {
+            raw: [0; LINE_SENSOR_COUNT],
+            mask,
+            position: None,
+        }
+    }
+
+    fn route(turns: &[Turn]) -> MazeRoute {
+        let mut route = MazeRoute::new();
+        for &turn in turns {
+            assert!(route.push(turn));
+        }
+        route
+    }
+
+    // Events for every sample, None where there wasn't one
+    fn updates(detector: &mut JunctionDetector, masks: &[u8]) -> [Option<MazeEvent>; 8] {
+        let mut events = [const { None }; 8];
+        for (event, &mask) in events.iter_mut().zip(masks) {
+            *event = detector.update(&line(mask));
+        }
+        events
+    }
+
+    #[test]
+    fn dead_ends_are_taken_out_of_the_route() {
+        use Turn::*;
+        assert!(route(&[Left, Back, Right]).turns() == [Back]);
+        assert!(route(&[Straight, Back, Left]).turns() == [Right]);
+        assert!(route(&[Left, Back, Left]).turns() == [Straight]);
+        assert!(route(&[Right, Back, Left]).turns() == [Back]);
+        // Nothing to take out before a Back
+        assert!(route(&[Left, Straight, Back]).turns() == [Left, Straight, Back]);
+    }
+
+    #[test]
+    fn reductions_chain_up() {
+        use Turn::*;
+        // A branch with only a dead end behind it is a dead end itself
+        assert!(route(&[Left, Left, Back, Right]).turns() == [Left, Back]);
+        assert!(route(&[Left, Left, Back, Right, Left]).turns() == [Straight]);
+        assert!(
+            route(&[Straight, Left, Left, Back, Right, Left, Right]).turns()
+                == [Straight, Straight, Right]
+        );
+    }
+
+    #[test]
+    fn full_route_is_refused() {
+        let mut route = route(&[Turn::Straight; MAX_ROUTE_LENGTH]);
+        assert!(!route.push(Turn::Left));
+        assert_eq!(route.turns().len(), MAX_ROUTE_LENGTH);
+    }
+
+    #[test]
+    fn branches_are_confirmed_after_a_few_samples() {
+        let mut detector = JunctionDetector::default();
+        let events = updates(
+            &mut detector,
+            &[CENTER, LEFT | CENTER, LEFT | CENTER, LEFT | CENTER],
+        );
+        assert!(events[..3].iter().all(Option::is_none));
+        assert!(matches!(
+            events[3],
+            Some(MazeEvent::Branch {
+                left: true,
+                right: false
+            })
+        ));
+
+        // The right side of a T can show up a sample later than the left one
+        let events = updates(
+            &mut detector,
+            &[LEFT | CENTER, LEFT | CENTER | RIGHT, CENTER | RIGHT],
+        );
+        assert!(matches!(
+            events[2],
+            Some(MazeEvent::Branch {
+                left: true,
+                right: true
+            })
+        ));
+    }
+
+    #[test]
+    fn short_branch_blips_are_ignored() {
+        let mut detector = JunctionDetector::default();
+        let events = updates(
+            &mut detector,
+            &[
+                RIGHT | CENTER,
+                RIGHT | CENTER,
+                CENTER,
+                LEFT | CENTER,
+                CENTER,
+            ],
+        );
+        assert!(events.iter().all(Option::is_none));
+        assert!(!detector.over_branch());
+    }
+
+    #[test]
+    fn dead_ends_need_the_line_gone_for_a_few_samples() {
+        let mut detector = JunctionDetector::default();
+        let events = updates(&mut detector, &[0, 0, CENTER, 0, 0, 0]);
+        assert!(events[..5].iter().all(Option::is_none));
+        assert!(matches!(events[5], Some(MazeEvent::DeadEnd)));
+    }
+}
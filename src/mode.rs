@@ -0,0 +1,81 @@
+use crate::recorder::{FIRST_RECORDING_KEY, RECORDING_COUNT};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, watch::Watch};
+
+// What the car is doing right now, selected with the number keys of the remote
+pub static MODE: Watch<ThreadModeRawMutex, Mode, 8> = Watch::new_with(Mode::Manual);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Manual,
+    LineFollow,
+    LineCalibration,
+    MazeExplore,
+    MazeSpeedRun,
+    // The remote edits the wheel calibration instead of driving
+    WheelTrim,
+    // Drive with the remote, then save the session under a number key
+    Record,
+    // Repeat the recording of a number key, from 0 to RECORDING_COUNT - 1
+    Replay(u8),
+    // Drive back to where the odometry started, selected by holding 0
+    ReturnToStart,
+    // Spin to learn how the car distorts the magnetic field, selected by holding 5
+    CompassCalibration,
+}
+
+impl Mode {
+    pub fn from_num(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Mode::Manual),
+            1 => Some(Mode::LineFollow),
+            2 => Some(Mode::LineCalibration),
+            3 => Some(Mode::MazeExplore),
+            4 => Some(Mode::MazeSpeedRun),
+            5 => Some(Mode::WheelTrim),
+            6 => Some(Mode::Record),
+            n if (FIRST_RECORDING_KEY..FIRST_RECORDING_KEY + RECORDING_COUNT).contains(&n) => {
+                Some(Mode::Replay(n - FIRST_RECORDING_KEY))
+            }
+            _ => None,
+        }
+    }
+
+    // Modes behind a number key held down, instead of pressed
+    pub fn from_held_num(n: u8) -> Option<Self> {
+        match n {
+            0 => Some(Mode::ReturnToStart),
+            5 => Some(Mode::CompassCalibration),
+            _ => None,
+        }
+    }
+
+    // Shown on the display: the key that selects the mode, or a name for the held ones
+    pub fn label(self) -> &'static str {
+        const KEYS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        match self {
+            Mode::Manual => KEYS[0],
+            Mode::LineFollow => KEYS[1],
+            Mode::LineCalibration => KEYS[2],
+            Mode::MazeExplore => KEYS[3],
+            Mode::MazeSpeedRun => KEYS[4],
+            Mode::WheelTrim => KEYS[5],
+            Mode::Record => KEYS[6],
+            Mode::Replay(n) => KEYS[(FIRST_RECORDING_KEY + n) as usize],
+            Mode::ReturnToStart => "HOME",
+            Mode::CompassCalibration => "COMPASS",
+        }
+    }
+
+    // Modes where the remote drives the car
+    pub fn is_manual(self) -> bool {
+        matches!(self, Mode::Manual | Mode::Record)
+    }
+
+    pub fn current() -> Self {
+        MODE.try_get().unwrap_or(Mode::Manual)
+    }
+
+    pub fn set(mode: Self) {
+        MODE.sender().send(mode);
+    }
+}
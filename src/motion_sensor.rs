@@ -0,0 +1,232 @@
+use embassy_nrf::{bind_interrupts, peripherals::TWISPI1, twim};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, watch::Watch};
+use embedded_hal_async::i2c::I2c;
+
+// The internal I2C bus of the micro:bit, only the motion sensor lives there for us
+bind_interrupts!(pub struct Irqs {
+    TWISPI1 => twim::InterruptHandler<TWISPI1>;
+});
+
+// LSM303AGR, accelerometer and magnetometer answer on their own addresses
+const ACCELEROMETER_ADDRESS: u8 = 0x19;
+const MAGNETOMETER_ADDRESS: u8 = 0x1E;
+const ACCELEROMETER_ID: u8 = 0x33;
+const MAGNETOMETER_ID: u8 = 0x40;
+// The accelerometer only moves to the next register when the address has this bit
+const AUTO_INCREMENT: u8 = 0x80;
+// Bit of both status registers telling a new sample of every axis is there
+const XYZ_DATA_READY: u8 = 0x08;
+
+// 50Hz, normal power with all the axes on
+const ACCELEROMETER_RATE: u8 = 0x47;
+// Data ready on INT1
+const ACCELEROMETER_INT1_DATA_READY: u8 = 0x10;
+// Samples don't mix halves of two readings, ±4g range, high resolution
+const ACCELEROMETER_RANGE: u8 = 0x98;
+// INT1 is low while data is ready, the line is shared with the interface chip
+const ACCELEROMETER_INT_ACTIVE_LOW: u8 = 0x02;
+// High resolution samples are 12 bits on the left of an i16, 2mg each at ±4g
+const MG_PER_DIGIT: i16 = 2;
+
+// 20Hz continuous, with the temperature compensation the datasheet asks for
+const MAGNETOMETER_RATE: u8 = 0x84;
+// Offset cancellation and the low-pass filter
+const MAGNETOMETER_FILTERS: u8 = 0x03;
+// Samples don't mix halves of two readings
+const MAGNETOMETER_BLOCK_UPDATE: u8 = 0x10;
+// The range is fixed at ±50 gauss, 1.5 milligauss per digit
+const UT_PER_DIGIT: f32 = 0.15;
+
+// Latest samples, in the axes of the sensor: x to the right edge of the micro:bit,
+// y to the top edge and z out of the front with the LEDs
+pub static ACCELERATION: Watch<ThreadModeRawMutex, Acceleration, 4> = Watch::new();
+pub static MAGNETIC_FIELD: Watch<ThreadModeRawMutex, MagneticField, 4> = Watch::new();
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Register {
+    WhoAmIA = 0x0F,
+    CtrlReg1A = 0x20,
+    CtrlReg3A = 0x22,
+    CtrlReg4A = 0x23,
+    CtrlReg6A = 0x25,
+    StatusRegA = 0x27,
+    OutXLA = 0x28,
+    WhoAmIM = 0x4F,
+    CfgRegAM = 0x60,
+    CfgRegBM = 0x61,
+    CfgRegCM = 0x62,
+    StatusRegM = 0x67,
+    OutXLM = 0x68,
+}
+
+impl Register {
+    const fn address(self) -> u8 {
+        self as u8
+    }
+}
+
+// In mg, 1000 is the gravity
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Acceleration {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+// In µT, the field of the earth is about 50
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub struct MagneticField {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum MotionSensorError<E> {
+    Bus(E),
+    // Something else answers on the address
+    WrongId { address: u8, id: u8 },
+}
+
+impl<E> From<E> for MotionSensorError<E> {
+    fn from(error: E) -> Self {
+        MotionSensorError::Bus(error)
+    }
+}
+
+// Works on any async I2C bus, the micro:bit has it on TWISPI1
+pub struct Lsm303agr<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Lsm303agr<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    pub async fn init(&mut self) -> Result<(), MotionSensorError<I2C::Error>> {
+        for (address, register, expected) in [
+            (ACCELEROMETER_ADDRESS, Register::WhoAmIA, ACCELEROMETER_ID),
+            (MAGNETOMETER_ADDRESS, Register::WhoAmIM, MAGNETOMETER_ID),
+        ] {
+            let id = self.read(address, register).await?;
+            if id != expected {
+                return Err(MotionSensorError::WrongId { address, id });
+            }
+        }
+
+        self.write(
+            ACCELEROMETER_ADDRESS,
+            Register::CtrlReg4A,
+            ACCELEROMETER_RANGE,
+        )
+        .await?;
+        self.write(
+            ACCELEROMETER_ADDRESS,
+            Register::CtrlReg6A,
+            ACCELEROMETER_INT_ACTIVE_LOW,
+        )
+        .await?;
+        self.write(
+            ACCELEROMETER_ADDRESS,
+            Register::CtrlReg3A,
+            ACCELEROMETER_INT1_DATA_READY,
+        )
+        .await?;
+        self.write(
+            ACCELEROMETER_ADDRESS,
+            Register::CtrlReg1A,
+            ACCELEROMETER_RATE,
+        )
+        .await?;
+
+        self.write(
+            MAGNETOMETER_ADDRESS,
+            Register::CfgRegCM,
+            MAGNETOMETER_BLOCK_UPDATE,
+        )
+        .await?;
+        self.write(
+            MAGNETOMETER_ADDRESS,
+            Register::CfgRegBM,
+            MAGNETOMETER_FILTERS,
+        )
+        .await?;
+        self.write(MAGNETOMETER_ADDRESS, Register::CfgRegAM, MAGNETOMETER_RATE)
+            .await?;
+        Ok(())
+    }
+
+    // None until a new sample is there
+    pub async fn acceleration(&mut self) -> Result<Option<Acceleration>, I2C::Error> {
+        if self
+            .read(ACCELEROMETER_ADDRESS, Register::StatusRegA)
+            .await?
+            & XYZ_DATA_READY
+            == 0
+        {
+            return Ok(None);
+        }
+        let [x, y, z] = self
+            .read_axes(
+                ACCELEROMETER_ADDRESS,
+                Register::OutXLA.address() | AUTO_INCREMENT,
+            )
+            .await?;
+        Ok(Some(Acceleration {
+            x: (x >> 4) * MG_PER_DIGIT,
+            y: (y >> 4) * MG_PER_DIGIT,
+            z: (z >> 4) * MG_PER_DIGIT,
+        }))
+    }
+
+    // None until a new sample is there
+    pub async fn magnetic_field(&mut self) -> Result<Option<MagneticField>, I2C::Error> {
+        if self
+            .read(MAGNETOMETER_ADDRESS, Register::StatusRegM)
+            .await?
+            & XYZ_DATA_READY
+            == 0
+        {
+            return Ok(None);
+        }
+        // The magnetometer always moves to the next register by itself
+        let [x, y, z] = self
+            .read_axes(MAGNETOMETER_ADDRESS, Register::OutXLM.address())
+            .await?;
+        Ok(Some(MagneticField {
+            x: x as f32 * UT_PER_DIGIT,
+            y: y as f32 * UT_PER_DIGIT,
+            z: z as f32 * UT_PER_DIGIT,
+        }))
+    }
+
+    async fn write(
+        &mut self,
+        address: u8,
+        register: Register,
+        value: u8,
+    ) -> Result<(), I2C::Error> {
+        self.i2c.write(address, &[register.address(), value]).await
+    }
+
+    async fn read(&mut self, address: u8, register: Register) -> Result<u8, I2C::Error> {
+        let mut value = [0u8];
+        self.i2c
+            .write_read(address, &[register.address()], &mut value)
+            .await?;
+        Ok(value[0])
+    }
+
+    // Little endian x, y and z from `first` on
+    async fn read_axes(&mut self, address: u8, first: u8) -> Result<[i16; 3], I2C::Error> {
+        let mut buffer = [0u8; 6];
+        self.i2c.write_read(address, &[first], &mut buffer).await?;
+        Ok([
+            i16::from_le_bytes([buffer[0], buffer[1]]),
+            i16::from_le_bytes([buffer[2], buffer[3]]),
+            i16::from_le_bytes([buffer[4], buffer[5]]),
+        ])
+    }
+}
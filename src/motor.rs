@@ -1,92 +1,688 @@
-use crate::twim::{TWIN_CHANNEL, TwinCommand};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
-use embassy_time::{Duration, Instant, Timer};
+use crate::{
+    compass::HEADING,
+    expander::{WHEEL_BRAKED, WHEEL_STOPPED, Wheel, WheelInputs},
+    math::wrap_angle,
+    odometry,
+    storage::{Record, Slot},
+    twim::{TwinClient, TwinError, TwinPriority},
+};
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, signal::Signal, watch::Watch,
+};
+use embassy_time::{Duration, Instant, Timer, with_deadline};
 
 pub static MOTORS_CHANNEL: Channel<ThreadModeRawMutex, MotorCommand, 1> = Channel::new();
+// Only the motors task talks to the motors
+static MOTORS_TWIN: TwinClient = TwinClient::new(TwinPriority::Motion);
+// Jumps ahead of any motion or lighting traffic still queued for the bus
+static EMERGENCY_TWIN: TwinClient = TwinClient::new(TwinPriority::Emergency);
+// How long StopMode::BrakeThenCoast holds the brake, enough to stop from full speed
+const BRAKE_BEFORE_COAST: Duration = Duration::from_millis(300);
+static NEXT_MANOEUVRE: AtomicU32 = AtomicU32::new(0);
+// Gain of a wheel running as commanded, in percent
+const FULL_GAIN: u8 = 100;
+const TRIM_GAIN_STEP: u8 = 2;
+const TRIM_DEADBAND_STEP: u8 = 0x10;
+const TRIM_DEADBAND_MAX: u8 = 0x80;
+const THROTTLE_STEP: i16 = 0x40;
+// Steering angle steps to each side, at full lock the inner wheels stand still
+const STEERING_LOCK: i8 = 4;
+// Close enough to the heading of a rotation, about 5°
+const ROTATION_TOLERANCE: f32 = 0.09;
+// Turn rate for every radian still to go, between the slowest rate that still turns
+// the car and the fastest one the compass keeps up with
+const ROTATION_GAIN: f32 = 0x80 as f32;
+const ROTATION_MIN_RATE: i16 = 0x50;
+const ROTATION_MAX_RATE: i16 = 0xA0;
+// Turn added for every radian a straight motion is off its heading, up to the limit
+const HEADING_HOLD_GAIN: f32 = 0x100 as f32;
+const HEADING_HOLD_MAX_TURN: i16 = 0x30;
 
+// Calibration applied by Motor::set_power, send a new one to replace it
+pub static WHEEL_CALIBRATION: Watch<ThreadModeRawMutex, WheelCalibration, 1> =
+    Watch::new_with(WheelCalibration::DEFAULT);
+// Edits from the remote while in Mode::WheelTrim
+pub static WHEEL_TRIM_CHANNEL: Channel<ThreadModeRawMutex, WheelTrimCommand, 1> = Channel::new();
+// What the wheels are doing, for anybody who wants to know without driving them
+pub static MOTION_STATE: Watch<ThreadModeRawMutex, MotionState, 4> =
+    Watch::new_with(MotionState::STOPPED);
+
+#[derive(Clone, Copy)]
 pub enum MotorCommand {
-    Stop,
+    Stop(StopMode),
     Forward,
     Backward,
     Left,
     Right,
+    Drive(Motion),
+    Steer(Steering),
+    Manoeuvre(Manoeuvre),
+    Rotate(Rotation),
 }
 
-impl MotorCommand {
-    pub async fn execute(&self) {
-        match self {
-            MotorCommand::Stop => {
-                for mut motor in Motor::all_motors().into_iter() {
-                    motor.set_power(MotorPower::Stop).await;
+// How a manoeuvre ended, with its number so the late end of an abandoned one isn't
+// taken for the next. Each task running manoeuvres needs its own, an end meant for
+// one task would wake up the other
+pub type ManoeuvreReply = Signal<ThreadModeRawMutex, (u32, ManoeuvreEnd)>;
+
+// A motion held for a while, then braked. Any newer command cuts it short
+#[derive(Clone, Copy)]
+pub struct Manoeuvre {
+    id: u32,
+    reply: Option<&'static ManoeuvreReply>,
+    pub motion: Motion,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ManoeuvreEnd {
+    Completed,
+    // Replaced by a newer command or a safety stop
+    Cancelled,
+    // Stuck, the car backed off a little so it can be tried again
+    Stalled,
+    Failed(MotorError),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MotorError {
+    Bus(TwinError),
+    // Rotations need the compass
+    NoHeading,
+    // A rotation didn't get there in time
+    TimedOut,
+}
+
+impl From<TwinError> for MotorError {
+    fn from(error: TwinError) -> Self {
+        MotorError::Bus(error)
+    }
+}
+
+// Spin on the spot until the compass says the car points the right way, then brake.
+// Any newer command cuts it short like a manoeuvre
+#[derive(Clone, Copy)]
+pub struct Rotation {
+    id: u32,
+    reply: Option<&'static ManoeuvreReply>,
+    target: RotationTarget,
+    timeout: Duration,
+}
+
+#[derive(Clone, Copy)]
+enum RotationTarget {
+    // Clockwise from the heading at the start of the rotation
+    By(f32),
+    // Clockwise from magnetic north
+    To(f32),
+}
+
+impl Manoeuvre {
+    pub fn new(motion: Motion, duration: Duration) -> Self {
+        Self {
+            id: NEXT_MANOEUVRE.fetch_add(1, Ordering::Relaxed),
+            reply: None,
+            motion,
+            duration,
+        }
+    }
+
+    // Negative speeds go backward
+    pub fn forward(speed: i16, duration: Duration) -> Self {
+        let motion = Motion {
+            forward: speed,
+            strafe: 0,
+            turn: 0,
+        };
+        Self::new(motion, duration)
+    }
+
+    // Spin on the spot, positive clockwise
+    pub fn rotate(rate: i16, duration: Duration) -> Self {
+        let motion = Motion {
+            forward: 0,
+            strafe: 0,
+            turn: rate,
+        };
+        Self::new(motion, duration)
+    }
+
+    // Hands the manoeuvre to the motors task and waits until it is over, so
+    // manoeuvres chain by running one after the other
+    pub async fn run(mut self, reply: &'static ManoeuvreReply) -> ManoeuvreEnd {
+        self.reply = Some(reply);
+        MOTORS_CHANNEL.send(MotorCommand::Manoeuvre(self)).await;
+        wait_for_end(reply, self.id).await
+    }
+}
+
+impl Rotation {
+    // Angles in radians, clockwise
+    pub fn by(angle: f32, timeout: Duration) -> Self {
+        Self::new(RotationTarget::By(angle), timeout)
+    }
+
+    pub fn to(heading: f32, timeout: Duration) -> Self {
+        Self::new(RotationTarget::To(heading), timeout)
+    }
+
+    fn new(target: RotationTarget, timeout: Duration) -> Self {
+        Self {
+            id: NEXT_MANOEUVRE.fetch_add(1, Ordering::Relaxed),
+            reply: None,
+            target,
+            timeout,
+        }
+    }
+
+    // Same as Manoeuvre::run
+    pub async fn run(mut self, reply: &'static ManoeuvreReply) -> ManoeuvreEnd {
+        self.reply = Some(reply);
+        MOTORS_CHANNEL.send(MotorCommand::Rotate(self)).await;
+        wait_for_end(reply, self.id).await
+    }
+
+    async fn execute(&self, motors: &mut Motors) -> Result<(), MotorError> {
+        let mut heading = HEADING.receiver().unwrap();
+        let Some(start) = heading.try_get() else {
+            return Err(MotorError::NoHeading);
+        };
+        let target = match self.target {
+            RotationTarget::By(angle) => wrap_angle(start + angle),
+            RotationTarget::To(heading) => heading,
+        };
+
+        let steer = async {
+            let mut current = start;
+            loop {
+                let error = wrap_angle(target - current);
+                if error.abs() <= ROTATION_TOLERANCE {
+                    return Ok(());
                 }
+                // Slower on the way in, so it doesn't overshoot
+                let rate = ((error * ROTATION_GAIN) as i16)
+                    .abs()
+                    .clamp(ROTATION_MIN_RATE, ROTATION_MAX_RATE);
+                let motion = Motion {
+                    forward: 0,
+                    strafe: 0,
+                    turn: if error < 0.0 { -rate } else { rate },
+                };
+                motors
+                    .write(motion.wheel_speeds().map(MotorPower::from_speed))
+                    .await?;
+                current = heading.changed().await;
             }
-            MotorCommand::Forward => {
-                for mut motor in Motor::all_motors().into_iter() {
-                    motor.set_power(MotorPower::Forward(0xFF)).await;
-                }
+        };
+        let result = with_deadline(Instant::now() + self.timeout, steer)
+            .await
+            .unwrap_or(Err(MotorError::TimedOut));
+        motors.write([MotorPower::Brake; 4]).await?;
+        result
+    }
+}
+
+// Steers a straight motion back to `locked` with every compass sample. Never done
+// unless the bus fails, and without a compass it only waits
+async fn hold_heading(
+    locked: Option<f32>,
+    motion: Motion,
+    motors: &mut Motors,
+) -> Result<(), MotorError> {
+    let Some(locked) = locked else {
+        return core::future::pending().await;
+    };
+    let mut heading = HEADING.receiver().unwrap();
+    let mut correction = 0;
+    loop {
+        let error = wrap_angle(locked - heading.changed().await);
+        let turn = ((error * HEADING_HOLD_GAIN) as i16)
+            .clamp(-HEADING_HOLD_MAX_TURN, HEADING_HOLD_MAX_TURN);
+        if turn != correction {
+            correction = turn;
+            let motion = Motion { turn, ..motion };
+            motors
+                .write(motion.wheel_speeds().map(MotorPower::from_speed))
+                .await?;
+        }
+    }
+}
+
+// How the manoeuvre or rotation with this number ended
+async fn wait_for_end(reply: &ManoeuvreReply, id: u32) -> ManoeuvreEnd {
+    loop {
+        let (end_id, end) = reply.wait().await;
+        if end_id == id {
+            return end;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StopMode {
+    // Let the wheels roll until they stop by themselves
+    Coast,
+    // Short the motors and keep them shorted
+    Brake,
+    // Brake for a moment, then let the wheels free so they can be pushed by hand
+    BrakeThenCoast,
+}
+
+// Mecanum motion, every component goes from -255 to 255
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Motion {
+    pub forward: i16,
+    pub strafe: i16, // positive to the right
+    pub turn: i16,   // positive clockwise
+}
+
+impl Motion {
+    // Mix the motion into each wheel speed, same order as Motor::all_motors()
+    fn wheel_speeds(&self) -> [i16; 4] {
+        let Motion {
+            forward,
+            strafe,
+            turn,
+        } = *self;
+        let speeds = [
+            forward - strafe - turn, // Front right
+            forward + strafe + turn, // Front left
+            forward + strafe - turn, // Back right
+            forward - strafe + turn, // Back left
+        ];
+        // Scale everything down together so the wheels keep their ratios
+        let max = speeds.iter().map(|speed| speed.abs()).max().unwrap_or(0);
+        if max > 0xFF {
+            speeds.map(|speed| (speed as i32 * 0xFF / max as i32) as i16)
+        } else {
+            speeds
+        }
+    }
+
+    // Along a straight line, moving but not turning
+    pub fn is_straight(&self) -> bool {
+        self.turn == 0 && (self.forward != 0 || self.strafe != 0)
+    }
+
+    // Undo the mixing of wheel_speeds, whatever doesn't fit a motion is averaged out
+    pub fn from_wheel_speeds(speeds: [i16; 4]) -> Self {
+        let [front_right, front_left, back_right, back_left] = speeds;
+        Motion {
+            forward: (front_right + front_left + back_right + back_left) / 4,
+            strafe: (-front_right + front_left + back_right - back_left) / 4,
+            turn: (-front_right + front_left - back_right + back_left) / 4,
+        }
+    }
+
+    // The strongest component wins
+    pub fn direction(&self) -> Direction {
+        let Motion {
+            forward,
+            strafe,
+            turn,
+        } = *self;
+        let strongest = forward.abs().max(strafe.abs()).max(turn.abs());
+        if strongest == 0 {
+            Direction::Stopped
+        } else if forward.abs() == strongest {
+            if forward > 0 {
+                Direction::Forward
+            } else {
+                Direction::Backward
             }
-            MotorCommand::Backward => {
-                for mut motor in Motor::all_motors().into_iter() {
-                    motor.set_power(MotorPower::Backward(0xFF)).await;
-                }
+        } else if strafe.abs() == strongest {
+            if strafe > 0 {
+                Direction::StrafeRight
+            } else {
+                Direction::StrafeLeft
+            }
+        } else if turn > 0 {
+            Direction::TurnRight
+        } else {
+            Direction::TurnLeft
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Direction {
+    Stopped,
+    Forward,
+    Backward,
+    StrafeLeft,
+    StrafeRight,
+    // On the spot or along an arc
+    TurnLeft,
+    TurnRight,
+}
+
+// Car-like driving, the car follows an arc set by the steering angle instead of
+// turning on the spot. The turn follows the throttle, so reversing steers like a car
+#[derive(Clone, Copy, Default)]
+pub struct Steering {
+    pub throttle: i16,
+    pub angle: i8, // positive to the right
+}
+
+impl Steering {
+    pub fn motion(&self) -> Motion {
+        Motion {
+            forward: self.throttle,
+            strafe: 0,
+            turn: self.throttle * self.angle as i16 / STEERING_LOCK as i16,
+        }
+    }
+
+    pub fn accelerate(&mut self) {
+        self.throttle = (self.throttle + THROTTLE_STEP).min(0xFF);
+    }
+
+    // Goes on into reverse once stopped
+    pub fn decelerate(&mut self) {
+        self.throttle = (self.throttle - THROTTLE_STEP).max(-0xFF);
+    }
+
+    pub fn steer_left(&mut self) {
+        self.angle = (self.angle - 1).max(-STEERING_LOCK);
+    }
+
+    pub fn steer_right(&mut self) {
+        self.angle = (self.angle + 1).min(STEERING_LOCK);
+    }
+}
+
+impl MotorCommand {
+    // Power of every wheel, same order as Motor::all_motors()
+    fn wheel_powers(&self) -> [MotorPower; 4] {
+        match self {
+            MotorCommand::Stop(StopMode::Coast) => [MotorPower::Coast; 4],
+            MotorCommand::Stop(StopMode::Brake | StopMode::BrakeThenCoast) => {
+                [MotorPower::Brake; 4]
             }
+            MotorCommand::Forward => [MotorPower::Forward(0xFF); 4],
+            MotorCommand::Backward => [MotorPower::Backward(0xFF); 4],
             MotorCommand::Left => {
-                for mut motor in Motor::left_side_motors().into_iter() {
-                    motor.set_power(MotorPower::Backward(0xFF)).await;
-                }
-                for mut motor in Motor::right_side_motors().into_iter() {
-                    motor.set_power(MotorPower::Forward(0xFF)).await;
-                }
+                Motor::powers_by_side(MotorPower::Backward(0xFF), MotorPower::Forward(0xFF))
             }
             MotorCommand::Right => {
-                for mut motor in Motor::left_side_motors().into_iter() {
-                    motor.set_power(MotorPower::Forward(0xFF)).await;
-                }
-                for mut motor in Motor::right_side_motors().into_iter() {
-                    motor.set_power(MotorPower::Backward(0xFF)).await;
-                }
+                Motor::powers_by_side(MotorPower::Forward(0xFF), MotorPower::Backward(0xFF))
+            }
+            MotorCommand::Drive(motion) => motion.wheel_speeds().map(MotorPower::from_speed),
+            MotorCommand::Steer(steering) => {
+                steering.motion().wheel_speeds().map(MotorPower::from_speed)
+            }
+            MotorCommand::Manoeuvre(manoeuvre) => {
+                manoeuvre.motion.wheel_speeds().map(MotorPower::from_speed)
+            }
+            // Rotations steer as they go, they start from standstill
+            MotorCommand::Rotate(_) => [MotorPower::Brake; 4],
+        }
+    }
+
+    // Straight motions from the remote, which the heading hold keeps straight. Modes
+    // driving on their own steer with Drive and Manoeuvre, the hold would fight them
+    fn straight_motion(&self) -> Option<Motion> {
+        let motion = match *self {
+            MotorCommand::Forward => Motion {
+                forward: 0xFF,
+                strafe: 0,
+                turn: 0,
+            },
+            MotorCommand::Backward => Motion {
+                forward: -0xFF,
+                strafe: 0,
+                turn: 0,
+            },
+            MotorCommand::Steer(steering) => steering.motion(),
+            _ => return None,
+        };
+        motion.is_straight().then_some(motion)
+    }
+
+    // Same as execute, except straight motions hold on to the heading they started on
+    // with the compass, until a newer command comes
+    pub async fn execute_holding_heading(&self, motors: &mut Motors) -> Result<(), MotorError> {
+        let Some(motion) = self.straight_motion() else {
+            return self.execute(motors).await;
+        };
+        let heading = HEADING.try_get();
+        motors
+            .write(motion.wheel_speeds().map(MotorPower::from_speed))
+            .await?;
+
+        hold_heading(heading, motion, motors).await
+    }
+
+    pub async fn execute(&self, motors: &mut Motors) -> Result<(), MotorError> {
+        if let MotorCommand::Rotate(rotation) = self {
+            return rotation.execute(motors).await;
+        }
+        motors.write(self.wheel_powers()).await?;
+
+        match self {
+            MotorCommand::Stop(StopMode::BrakeThenCoast) => {
+                Timer::after(BRAKE_BEFORE_COAST).await;
+                Ok(motors.write([MotorPower::Coast; 4]).await?)
+            }
+            MotorCommand::Manoeuvre(manoeuvre) => {
+                Timer::after(manoeuvre.duration).await;
+                Ok(motors.write([MotorPower::Brake; 4]).await?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Tell whoever waits for a manoeuvre or a rotation how it went, nothing to do for
+    // other commands or when nobody waits
+    pub fn report(&self, end: ManoeuvreEnd) {
+        let (id, reply) = match self {
+            MotorCommand::Manoeuvre(manoeuvre) => (manoeuvre.id, manoeuvre.reply),
+            MotorCommand::Rotate(rotation) => (rotation.id, rotation.reply),
+            _ => return,
+        };
+        if let Some(reply) = reply {
+            reply.signal((id, end));
+        }
+    }
+}
+
+// How a single wheel differs from the ideal one
+#[derive(Clone, Copy, defmt::Format)]
+pub struct WheelTrim {
+    // The motor is wired backwards
+    pub inverted: bool,
+    // Percent of the commanded speed, to make the wheels match
+    pub gain: u8,
+    // Lowest value that still turns the wheel, any speed above 0 starts here
+    pub deadband: u8,
+}
+
+impl WheelTrim {
+    const DEFAULT: Self = Self {
+        inverted: false,
+        gain: FULL_GAIN,
+        deadband: 0x00,
+    };
+
+    fn apply(&self, power: MotorPower) -> MotorPower {
+        let (forward, speed) = match power {
+            MotorPower::Forward(speed) => (true, speed),
+            MotorPower::Backward(speed) => (false, speed),
+            MotorPower::Coast | MotorPower::Brake => return power,
+        };
+        let speed = speed as u32 * self.gain as u32 / FULL_GAIN as u32;
+        let speed = if speed == 0 {
+            0
+        } else {
+            let deadband = self.deadband as u32;
+            (deadband + speed * (0xFF - deadband) / 0xFF).min(0xFF) as u8
+        };
+        if forward != self.inverted {
+            MotorPower::Forward(speed)
+        } else {
+            MotorPower::Backward(speed)
+        }
+    }
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub struct WheelCalibration {
+    // Same order as Wheel::ALL
+    pub wheels: [WheelTrim; 4],
+}
+
+impl WheelCalibration {
+    pub const DEFAULT: Self = Self {
+        wheels: [WheelTrim::DEFAULT; 4],
+    };
+
+    fn current() -> Self {
+        WHEEL_CALIBRATION.try_get().unwrap_or(Self::DEFAULT)
+    }
+
+    // A car drifting to the other side is steered back by slowing down the side it
+    // steers to, or by giving back the speed taken from the other side first
+    pub fn steer(&mut self, side: MotorSide) {
+        let other = side.other();
+        let other_slowed = Motor::all_motors()
+            .iter()
+            .any(|motor| motor.side == other && self.trim(motor).gain < FULL_GAIN);
+        for motor in Motor::all_motors() {
+            let trim = &mut self.wheels[motor.wheel().index()];
+            if other_slowed && motor.side == other {
+                trim.gain = (trim.gain + TRIM_GAIN_STEP).min(FULL_GAIN);
+            } else if !other_slowed && motor.side == side {
+                trim.gain = trim.gain.saturating_sub(TRIM_GAIN_STEP);
             }
         }
     }
+
+    pub fn toggle_inverted(&mut self, wheel: Wheel) {
+        let trim = &mut self.wheels[wheel.index()];
+        trim.inverted = !trim.inverted;
+    }
+
+    // Raise the deadband of the wheel, back to none after the highest
+    pub fn next_deadband(&mut self, wheel: Wheel) {
+        let trim = &mut self.wheels[wheel.index()];
+        trim.deadband = if trim.deadband >= TRIM_DEADBAND_MAX {
+            0x00
+        } else {
+            trim.deadband + TRIM_DEADBAND_STEP
+        };
+    }
+
+    fn trim(&self, motor: &Motor) -> &WheelTrim {
+        &self.wheels[motor.wheel().index()]
+    }
 }
 
-#[derive(Clone, Copy)]
-enum MotorSide {
+impl Record for WheelCalibration {
+    const SLOT: Slot = Slot::WheelCalibration;
+    const SIZE: usize = 4 * 3;
+
+    fn encode(&self, buf: &mut [u8]) {
+        for (trim, chunk) in self.wheels.iter().zip(buf.as_chunks_mut::<3>().0) {
+            *chunk = [trim.inverted as u8, trim.gain, trim.deadband];
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut calibration = Self::DEFAULT;
+        for (trim, chunk) in calibration.wheels.iter_mut().zip(buf.as_chunks::<3>().0) {
+            *trim = WheelTrim {
+                inverted: chunk[0] != 0,
+                gain: chunk[1],
+                deadband: chunk[2],
+            };
+        }
+        Some(calibration)
+    }
+}
+
+pub enum WheelTrimCommand {
+    Steer(MotorSide),
+    ToggleInverted(Wheel),
+    // The deadband keys work on one wheel at a time
+    SelectNextWheel,
+    NextDeadband,
+    // Drive straight at full speed to see where the car drifts
+    TestDrive,
+    TestStop,
+    Save,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MotorSide {
     Left,
     Right,
 }
 
+impl MotorSide {
+    const fn other(self) -> Self {
+        match self {
+            MotorSide::Left => MotorSide::Right,
+            MotorSide::Right => MotorSide::Left,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum MotorPosition {
     Front,
     Back,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum MotorPower {
-    Stop,
+    Coast,
+    Brake,
     Forward(u8),  // speed 0-100
     Backward(u8), // speed 0-100
 }
 
+impl MotorPower {
+    // Signed speed, negative goes backward
+    pub fn from_speed(speed: i16) -> Self {
+        match speed {
+            0 => MotorPower::Coast,
+            speed if speed > 0 => MotorPower::Forward(speed.min(0xFF) as u8),
+            speed => MotorPower::Backward(speed.unsigned_abs().min(0xFF) as u8),
+        }
+    }
+
+    // Signed speed the wheel is asked for, stopped wheels count as 0 however they stop
+    pub fn speed(self) -> i16 {
+        match self {
+            MotorPower::Coast | MotorPower::Brake => 0,
+            MotorPower::Forward(speed) => speed as i16,
+            MotorPower::Backward(speed) => -(speed as i16),
+        }
+    }
+}
+
 pub struct Motor {
     side: MotorSide,
     position: MotorPosition,
+    // What the wheel was asked for, before the calibration
+    target: MotorPower,
+    // What the motor driver was last told, after the calibration
     power: MotorPower,
 }
 
 impl Motor {
-    pub const FRONT_RIGHT: Self =
-        Self::new(MotorSide::Right, MotorPosition::Front, MotorPower::Stop);
-    pub const FRONT_LEFT: Self = Self::new(MotorSide::Left, MotorPosition::Front, MotorPower::Stop);
-    pub const BACK_RIGHT: Self = Self::new(MotorSide::Right, MotorPosition::Back, MotorPower::Stop);
-    pub const BACK_LEFT: Self = Self::new(MotorSide::Left, MotorPosition::Back, MotorPower::Stop);
+    pub const FRONT_RIGHT: Self = Self::new(MotorSide::Right, MotorPosition::Front);
+    pub const FRONT_LEFT: Self = Self::new(MotorSide::Left, MotorPosition::Front);
+    pub const BACK_RIGHT: Self = Self::new(MotorSide::Right, MotorPosition::Back);
+    pub const BACK_LEFT: Self = Self::new(MotorSide::Left, MotorPosition::Back);
 
-    const fn new(side: MotorSide, position: MotorPosition, power: MotorPower) -> Self {
+    const fn new(side: MotorSide, position: MotorPosition) -> Self {
         Self {
             side,
             position,
-            power,
+            target: MotorPower::Coast,
+            power: MotorPower::Coast,
         }
     }
 
@@ -99,31 +695,272 @@ impl Motor {
         ]
     }
 
-    pub const fn left_side_motors() -> [Self; 2] {
-        [Self::FRONT_LEFT, Self::BACK_LEFT]
+    fn powers_by_side(left: MotorPower, right: MotorPower) -> [MotorPower; 4] {
+        Self::all_motors().map(|motor| match motor.side {
+            MotorSide::Left => left,
+            MotorSide::Right => right,
+        })
     }
 
-    pub const fn right_side_motors() -> [Self; 2] {
-        [Self::FRONT_RIGHT, Self::BACK_RIGHT]
+    const fn wheel(&self) -> Wheel {
+        match (self.position, self.side) {
+            (MotorPosition::Front, MotorSide::Right) => Wheel::FrontRight,
+            (MotorPosition::Front, MotorSide::Left) => Wheel::FrontLeft,
+            (MotorPosition::Back, MotorSide::Right) => Wheel::BackRight,
+            (MotorPosition::Back, MotorSide::Left) => Wheel::BackLeft,
+        }
     }
 
-    const fn channel(&self) -> (u8, u8) {
-        match (self.position, self.side) {
-            (MotorPosition::Front, MotorSide::Right) => (0x01, 0x02),
-            (MotorPosition::Front, MotorSide::Left) => (0x03, 0x04),
-            (MotorPosition::Back, MotorSide::Right) => (0x05, 0x06),
-            (MotorPosition::Back, MotorSide::Left) => (0x07, 0x08),
+    // Values for both inputs of the motor driver
+    const fn values(power: MotorPower) -> WheelInputs {
+        match power {
+            MotorPower::Coast => WHEEL_STOPPED,
+            MotorPower::Brake => WHEEL_BRAKED,
+            MotorPower::Forward(speed) => (0x00, speed),
+            MotorPower::Backward(speed) => (speed, 0x00),
         }
     }
 
-    pub async fn set_power(&mut self, power: MotorPower) {
-        self.power = power;
-        let ((channel0, value0), (channel1, value1)) = match power {
-            MotorPower::Stop => ((self.channel().0, 0x00), (self.channel().1, 0x00)),
-            MotorPower::Forward(speed) => ((self.channel().0, 0x00), (self.channel().1, speed)),
-            MotorPower::Backward(speed) => ((self.channel().0, speed), (self.channel().1, 0x00)),
+    const fn state(&self) -> WheelState {
+        WheelState {
+            target: self.target,
+            power: self.power,
+        }
+    }
+}
+
+// The four motors as they are right now. The motors task owns the only one, everybody
+// else watches MOTION_STATE
+pub struct Motors {
+    motors: [Motor; 4],
+}
+
+impl Motors {
+    pub const fn new() -> Self {
+        Self {
+            motors: Motor::all_motors(),
+        }
+    }
+
+    // Same order as Motor::all_motors(). The wheel calibration is applied here, so
+    // everybody else can pretend all the wheels are the same
+    async fn write(&mut self, targets: [MotorPower; 4]) -> Result<(), TwinError> {
+        let calibration = WheelCalibration::current();
+        let mut powers = targets;
+        let mut inputs = [WHEEL_STOPPED; 4];
+        for ((motor, target), power) in self.motors.iter_mut().zip(targets).zip(&mut powers) {
+            motor.target = target;
+            *power = calibration.trim(motor).apply(target);
+            inputs[motor.wheel().index()] = Motor::values(*power);
+        }
+        self.publish();
+
+        // All the motor registers in a single bus transaction, so every wheel changes at once
+        MOTORS_TWIN.set_wheels(inputs).await?;
+        for (motor, power) in self.motors.iter_mut().zip(powers) {
+            motor.power = power;
+        }
+        self.publish();
+        // Untrimmed, the odometry assumes the calibration made all the wheels the same
+        odometry::set_wheel_speeds(targets.map(MotorPower::speed));
+        Ok(())
+    }
+
+    // Same as braking all the motors, on the emergency lane
+    pub async fn emergency_stop(&mut self) -> Result<(), TwinError> {
+        for motor in &mut self.motors {
+            motor.target = MotorPower::Brake;
+        }
+        self.publish();
+
+        EMERGENCY_TWIN.set_wheels([WHEEL_BRAKED; 4]).await?;
+        for motor in &mut self.motors {
+            motor.power = MotorPower::Brake;
+        }
+        self.publish();
+        odometry::set_wheel_speeds([0; 4]);
+        Ok(())
+    }
+
+    pub fn state(&self) -> MotionState {
+        MotionState {
+            wheels: self.motors.each_ref().map(Motor::state),
+        }
+    }
+
+    fn publish(&self) {
+        MOTION_STATE.sender().send_if_modified(|state| {
+            let new = self.state();
+            let modified = *state != Some(new);
+            *state = Some(new);
+            modified
+        });
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct WheelState {
+    pub target: MotorPower,
+    // Differs from the target by the calibration, or while the bus is catching up
+    pub power: MotorPower,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct MotionState {
+    // Same order as Motor::all_motors()
+    pub wheels: [WheelState; 4],
+}
+
+impl MotionState {
+    const STOPPED: Self = Self {
+        wheels: [WheelState {
+            target: MotorPower::Coast,
+            power: MotorPower::Coast,
+        }; 4],
+    };
+
+    // What the car as a whole was asked to do
+    pub fn motion(&self) -> Motion {
+        Motion::from_wheel_speeds(self.wheels.map(|wheel| wheel.target.speed()))
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.motion().direction()
+    }
+
+    // Some wheel is still driven, braking and coasting wheels don't count
+    pub fn is_moving(&self) -> bool {
+        self.wheels.iter().any(|wheel| wheel.power.speed() != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIMMED: WheelTrim = WheelTrim {
+        inverted: false,
+        gain: 50,
+        deadband: 0x40,
+    };
+
+    #[test]
+    fn default_trim_changes_nothing() {
+        for power in [
+            MotorPower::Coast,
+            MotorPower::Brake,
+            MotorPower::Forward(0x80),
+            MotorPower::Backward(0xFF),
+        ] {
+            assert!(WheelTrim::DEFAULT.apply(power) == power);
+        }
+    }
+
+    #[test]
+    fn inverted_wheels_turn_the_other_way() {
+        let inverted = WheelTrim {
+            inverted: true,
+            ..WheelTrim::DEFAULT
+        };
+        assert!(inverted.apply(MotorPower::Forward(0x80)) == MotorPower::Backward(0x80));
+        assert!(inverted.apply(MotorPower::Backward(0x20)) == MotorPower::Forward(0x20));
+        assert!(inverted.apply(MotorPower::Brake) == MotorPower::Brake);
+    }
+
+    #[test]
+    fn gain_and_deadband_rescale_the_speed() {
+        let gain_only = WheelTrim {
+            deadband: 0,
+            ..TRIMMED
+        };
+        assert!(gain_only.apply(MotorPower::Forward(200)) == MotorPower::Forward(100));
+        // The smallest speed starts at the deadband, a stopped wheel stays stopped
+        assert!(TRIMMED.apply(MotorPower::Forward(2)) == MotorPower::Forward(0x40));
+        assert!(TRIMMED.apply(MotorPower::Backward(0)) == MotorPower::Backward(0));
+        let full_gain = WheelTrim {
+            gain: FULL_GAIN,
+            ..TRIMMED
+        };
+        assert!(full_gain.apply(MotorPower::Forward(0xFF)) == MotorPower::Forward(0xFF));
+    }
+
+    #[test]
+    fn gain_above_full_saturates() {
+        let boosted = WheelTrim {
+            gain: 150,
+            ..TRIMMED
         };
-        TWIN_CHANNEL.send(TwinCommand::new(channel0, value0)).await;
-        TWIN_CHANNEL.send(TwinCommand::new(channel1, value1)).await;
+        assert!(boosted.apply(MotorPower::Forward(0xC0)) == MotorPower::Forward(0xFF));
+        assert!(boosted.apply(MotorPower::Backward(0xFF)) == MotorPower::Backward(0xFF));
+    }
+
+    fn same_motion(a: Motion, b: Motion) -> bool {
+        (a.forward, a.strafe, a.turn) == (b.forward, b.strafe, b.turn)
+    }
+
+    #[test]
+    fn wheel_speeds_mix_back_into_the_same_motion() {
+        let motions = [
+            Motion {
+                forward: 100,
+                strafe: -50,
+                turn: 30,
+            },
+            Motion {
+                forward: -80,
+                strafe: 0,
+                turn: 0,
+            },
+            Motion {
+                forward: 0,
+                strafe: 120,
+                turn: -120,
+            },
+        ];
+        for motion in motions {
+            let round_trip = Motion::from_wheel_speeds(motion.wheel_speeds());
+            assert!(same_motion(round_trip, motion));
+        }
+    }
+
+    #[test]
+    fn wheel_speeds_scale_down_together() {
+        let motion = Motion {
+            forward: 200,
+            strafe: 100,
+            turn: 0,
+        };
+        assert_eq!(motion.wheel_speeds(), [85, 255, 255, 85]);
+
+        // Even with every component at full, no wheel goes past full speed and the direction holds
+        for forward in [-255, 0, 255] {
+            for strafe in [-255, 0, 255] {
+                for turn in [-255, 0, 255] {
+                    let motion = Motion {
+                        forward,
+                        strafe,
+                        turn,
+                    };
+                    let speeds = motion.wheel_speeds();
+                    assert!(speeds.iter().all(|speed| speed.abs() <= 0xFF));
+                    let mixed = Motion::from_wheel_speeds(speeds);
+                    assert!(mixed.direction() == motion.direction());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leftover_wheel_speeds_are_averaged_out() {
+        // Only the front right wheel turns, that's a bit of every component
+        let motion = Motion::from_wheel_speeds([200, 0, 0, 0]);
+        assert!(same_motion(
+            motion,
+            Motion {
+                forward: 50,
+                strafe: -50,
+                turn: -50,
+            }
+        ));
     }
 }
@@ -0,0 +1,141 @@
+use crate::math::{cos, sin, wrap_angle};
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::{Mutex, raw::ThreadModeRawMutex};
+use embassy_time::{Duration, Instant};
+
+// Ground speed for each unit of wheel speed, measured on a charged battery.
+// There are no encoders, so this is all the odometry knows about the real speed
+const MM_PER_S_PER_SPEED: f32 = 1.8;
+// Turn rate for each unit of the turn component, the wheels slip a lot while spinning
+const RAD_PER_S_PER_SPEED: f32 = 0.016;
+// Longest step integrated at once, so long arcs stay arcs
+const MAX_STEP: Duration = Duration::from_millis(20);
+// Share of the difference with the compass taken over with each of its samples. The
+// motors disturb the compass, so it only keeps the heading from drifting away
+const COMPASS_CORRECTION: f32 = 0.05;
+
+static ODOMETRY: Mutex<ThreadModeRawMutex, RefCell<Odometry>> =
+    Mutex::new(RefCell::new(Odometry::new()));
+
+// Where the car is, relative to where it was switched on or last came back to
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Pose {
+    // In mm, x forward and y to the right of the starting direction
+    pub x: f32,
+    pub y: f32,
+    // Clockwise from the starting direction, -PI..=PI
+    pub heading: f32,
+}
+
+impl Pose {
+    const START: Self = Self {
+        x: 0.0,
+        y: 0.0,
+        heading: 0.0,
+    };
+}
+
+struct Odometry {
+    pose: Pose,
+    // Commanded speed of every wheel since `since`, same order as Motor::all_motors()
+    speeds: [i16; 4],
+    since: Option<Instant>,
+    // Compass heading of the starting direction, once the compass has been heard from
+    start_heading: Option<f32>,
+}
+
+impl Odometry {
+    const fn new() -> Self {
+        Self {
+            pose: Pose::START,
+            speeds: [0; 4],
+            since: None,
+            start_heading: None,
+        }
+    }
+
+    // Integrate the current wheel speeds up to `now`
+    fn advance(&mut self, now: Instant) {
+        let Some(mut since) = self.since else {
+            self.since = Some(now);
+            return;
+        };
+        // Same mixing as Motion::wheel_speeds, the other way round
+        let [front_right, front_left, back_right, back_left] =
+            self.speeds.map(|speed| speed as f32);
+        let forward =
+            (front_right + front_left + back_right + back_left) / 4.0 * MM_PER_S_PER_SPEED;
+        let strafe =
+            (-front_right + front_left + back_right - back_left) / 4.0 * MM_PER_S_PER_SPEED;
+        let turn = (-front_right + front_left - back_right + back_left) / 4.0 * RAD_PER_S_PER_SPEED;
+
+        while since < now {
+            let step = (now - since).min(MAX_STEP);
+            let dt = step.as_micros() as f32 / 1_000_000.0;
+            // Moving along the heading halfway through the step follows the arc closely
+            let heading = self.pose.heading + turn * dt / 2.0;
+            self.pose.x += (forward * cos(heading) - strafe * sin(heading)) * dt;
+            self.pose.y += (forward * sin(heading) + strafe * cos(heading)) * dt;
+            self.pose.heading = wrap_angle(self.pose.heading + turn * dt);
+            since += step;
+        }
+        self.since = Some(now);
+    }
+}
+
+// Called with every change of the wheels, the pose moves on with the previous speeds until then
+pub fn set_wheel_speeds(speeds: [i16; 4]) {
+    ODOMETRY.lock(|odometry| {
+        let mut odometry = odometry.borrow_mut();
+        odometry.advance(Instant::now());
+        odometry.speeds = speeds;
+    });
+}
+
+pub fn pose() -> Pose {
+    ODOMETRY.lock(|odometry| {
+        let mut odometry = odometry.borrow_mut();
+        odometry.advance(Instant::now());
+        odometry.pose
+    })
+}
+
+// The car is back where it started, as far as anybody can tell
+pub fn reset() {
+    ODOMETRY.lock(|odometry| {
+        let mut odometry = odometry.borrow_mut();
+        odometry.advance(Instant::now());
+        odometry.pose = Pose::START;
+        odometry.start_heading = None;
+    });
+}
+
+// Pull the heading towards the compass, which knows nothing about where the car started
+pub fn correct_heading(compass: f32) {
+    ODOMETRY.lock(|odometry| {
+        let mut odometry = odometry.borrow_mut();
+        odometry.advance(Instant::now());
+        let heading = odometry.pose.heading;
+        let start_heading = *odometry
+            .start_heading
+            .get_or_insert(wrap_angle(compass - heading));
+        let error = wrap_angle(compass - start_heading - heading);
+        odometry.pose.heading = wrap_angle(heading + error * COMPASS_CORRECTION);
+    });
+}
+
+pub fn start_heading() -> Option<f32> {
+    ODOMETRY.lock(|odometry| odometry.borrow().start_heading)
+}
+
+// How long to drive straight at `speed` to cover `distance` mm
+pub fn time_to_drive(distance: f32, speed: i16) -> Duration {
+    let mm_per_s = (speed.unsigned_abs() as f32 * MM_PER_S_PER_SPEED).max(1.0);
+    Duration::from_micros((distance.abs() / mm_per_s * 1_000_000.0) as u64)
+}
+
+// How long to spin at `rate` to turn by `angle` radians
+pub fn time_to_turn(angle: f32, rate: i16) -> Duration {
+    let rad_per_s = (rate.unsigned_abs() as f32 * RAD_PER_S_PER_SPEED).max(0.01);
+    Duration::from_micros((angle.abs() / rad_per_s * 1_000_000.0) as u64)
+}
@@ -0,0 +1,233 @@
+use crate::{
+    big_led::BigLedCommand,
+    motor::{Motion, MotorCommand, Steering, StopMode},
+    storage::{Record, Slot},
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::Instant;
+
+// Number keys the recordings are assigned to, from the first one on
+pub const FIRST_RECORDING_KEY: u8 = 7;
+pub const RECORDING_COUNT: u8 = 3;
+pub const MAX_RECORDING_STEPS: usize = 64;
+// Delay, kind and payload of every step
+const STEP_SIZE: usize = 9;
+
+// What the remote does while Mode::Record is on
+pub static RECORDER_CHANNEL: Channel<ThreadModeRawMutex, RecorderEvent, 4> = Channel::new();
+
+pub enum RecorderEvent {
+    Step(Instant, Action),
+    // Keep the recording under the number key, from 0 to RECORDING_COUNT - 1
+    Save(u8),
+}
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    Motor(MotorCommand),
+    BigLeds(BigLedCommand),
+}
+
+impl Action {
+    // Manoeuvres and rotations are never sent by the remote, so they aren't recorded
+    fn encode(&self) -> Option<[u8; 7]> {
+        let (kind, a, b, c) = match *self {
+            Action::Motor(MotorCommand::Stop(StopMode::Coast)) => (0x00, 0, 0, 0),
+            Action::Motor(MotorCommand::Stop(StopMode::Brake)) => (0x01, 0, 0, 0),
+            Action::Motor(MotorCommand::Stop(StopMode::BrakeThenCoast)) => (0x02, 0, 0, 0),
+            Action::Motor(MotorCommand::Forward) => (0x03, 0, 0, 0),
+            Action::Motor(MotorCommand::Backward) => (0x04, 0, 0, 0),
+            Action::Motor(MotorCommand::Left) => (0x05, 0, 0, 0),
+            Action::Motor(MotorCommand::Right) => (0x06, 0, 0, 0),
+            Action::Motor(MotorCommand::Drive(motion)) => {
+                (0x07, motion.forward, motion.strafe, motion.turn)
+            }
+            Action::Motor(MotorCommand::Steer(steering)) => {
+                (0x08, steering.throttle, steering.angle as i16, 0)
+            }
+            Action::Motor(MotorCommand::Manoeuvre(_) | MotorCommand::Rotate(_)) => return None,
+            Action::BigLeds(BigLedCommand::Toggle) => (0x10, 0, 0, 0),
+            Action::BigLeds(BigLedCommand::Brightness(value)) => (0x11, value as i16, 0, 0),
+        };
+        let [a0, a1] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        let [c0, c1] = c.to_le_bytes();
+        Some([kind, a0, a1, b0, b1, c0, c1])
+    }
+
+    fn decode(buf: &[u8; 7]) -> Option<Self> {
+        let a = i16::from_le_bytes([buf[1], buf[2]]);
+        let b = i16::from_le_bytes([buf[3], buf[4]]);
+        let c = i16::from_le_bytes([buf[5], buf[6]]);
+        let action = match buf[0] {
+            0x00 => Action::Motor(MotorCommand::Stop(StopMode::Coast)),
+            0x01 => Action::Motor(MotorCommand::Stop(StopMode::Brake)),
+            0x02 => Action::Motor(MotorCommand::Stop(StopMode::BrakeThenCoast)),
+            0x03 => Action::Motor(MotorCommand::Forward),
+            0x04 => Action::Motor(MotorCommand::Backward),
+            0x05 => Action::Motor(MotorCommand::Left),
+            0x06 => Action::Motor(MotorCommand::Right),
+            0x07 => Action::Motor(MotorCommand::Drive(Motion {
+                forward: a,
+                strafe: b,
+                turn: c,
+            })),
+            0x08 => Action::Motor(MotorCommand::Steer(Steering {
+                throttle: a,
+                angle: b as i8,
+            })),
+            0x10 => Action::BigLeds(BigLedCommand::Toggle),
+            0x11 => Action::BigLeds(BigLedCommand::Brightness(a as u8)),
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Step {
+    // Since the step before, the first one starts right away
+    pub delay_ms: u16,
+    pub action: Action,
+}
+
+// A driving session as it came from the remote
+pub struct Recording {
+    steps: [Step; MAX_RECORDING_STEPS],
+    len: usize,
+}
+
+impl Recording {
+    pub const fn new() -> Self {
+        Self {
+            steps: [Step {
+                delay_ms: 0,
+                action: Action::Motor(MotorCommand::Stop(StopMode::Coast)),
+            }; MAX_RECORDING_STEPS],
+            len: 0,
+        }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps[..self.len]
+    }
+
+    // False if the recording is full. Longer pauses than a u16 of milliseconds are cut short
+    pub fn push(&mut self, delay_ms: u64, action: Action) -> bool {
+        if self.len == MAX_RECORDING_STEPS || action.encode().is_none() {
+            return false;
+        }
+        self.steps[self.len] = Step {
+            delay_ms: delay_ms.min(u16::MAX as u64) as u16,
+            action,
+        };
+        self.len += 1;
+        true
+    }
+}
+
+impl Record for Recording {
+    // Only a default, every recording goes to the slot of its number key
+    const SLOT: Slot = Slot::Recording(0);
+    const SIZE: usize = 1 + MAX_RECORDING_STEPS * STEP_SIZE;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.len as u8;
+        for (step, chunk) in self
+            .steps()
+            .iter()
+            .zip(buf[1..].as_chunks_mut::<STEP_SIZE>().0)
+        {
+            chunk[..2].copy_from_slice(&step.delay_ms.to_le_bytes());
+            // Only encodable actions get pushed
+            chunk[2..].copy_from_slice(&step.action.encode().unwrap_or_default());
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut recording = Self::new();
+        let len = buf[0] as usize;
+        if len > MAX_RECORDING_STEPS {
+            return None;
+        }
+        for chunk in buf[1..].as_chunks::<STEP_SIZE>().0.iter().take(len) {
+            let delay_ms = u16::from_le_bytes([chunk[0], chunk[1]]);
+            let action = Action::decode(chunk[2..].try_into().ok()?)?;
+            if !recording.push(delay_ms as u64, action) {
+                return None;
+            }
+        }
+        Some(recording)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(recording: &Recording) -> [u8; Recording::SIZE] {
+        let mut buf = [0u8; Recording::SIZE];
+        recording.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn round_trips_through_its_encoding() {
+        let mut recording = Recording::new();
+        let actions = [
+            Action::Motor(MotorCommand::Forward),
+            Action::Motor(MotorCommand::Drive(Motion {
+                forward: 300,
+                strafe: -150,
+                turn: -1000,
+            })),
+            Action::Motor(MotorCommand::Steer(Steering {
+                throttle: -500,
+                angle: -45,
+            })),
+            Action::BigLeds(BigLedCommand::Brightness(200)),
+            Action::Motor(MotorCommand::Stop(StopMode::BrakeThenCoast)),
+        ];
+        for (i, action) in actions.into_iter().enumerate() {
+            assert!(recording.push(i as u64 * 250, action));
+        }
+
+        let decoded = Recording::decode(&encoded(&recording)).unwrap();
+        assert_eq!(decoded.steps().len(), actions.len());
+        for (step, (i, action)) in decoded.steps().iter().zip(actions.iter().enumerate()) {
+            assert_eq!(step.delay_ms, i as u16 * 250);
+            assert_eq!(step.action.encode(), action.encode());
+        }
+    }
+
+    #[test]
+    fn caps_long_pauses_and_full_recordings() {
+        let mut recording = Recording::new();
+        assert!(recording.push(100_000, Action::Motor(MotorCommand::Forward)));
+        assert_eq!(recording.steps()[0].delay_ms, u16::MAX);
+
+        for _ in 1..MAX_RECORDING_STEPS {
+            assert!(recording.push(0, Action::Motor(MotorCommand::Left)));
+        }
+        assert!(!recording.push(0, Action::Motor(MotorCommand::Right)));
+        assert_eq!(recording.steps().len(), MAX_RECORDING_STEPS);
+    }
+
+    #[test]
+    fn rejects_corrupt_steps_and_lengths() {
+        let mut recording = Recording::new();
+        recording.push(0, Action::Motor(MotorCommand::Forward));
+        recording.push(500, Action::Motor(MotorCommand::Right));
+
+        let mut unknown_action = encoded(&recording);
+        unknown_action[1 + STEP_SIZE + 2] = 0x7f;
+        assert!(Recording::decode(&unknown_action).is_none());
+
+        let mut too_long = encoded(&recording);
+        too_long[0] = MAX_RECORDING_STEPS as u8 + 1;
+        assert!(Recording::decode(&too_long).is_none());
+
+        // Erased flash reads as all ones
+        assert!(Recording::decode(&[0xff; Recording::SIZE]).is_none());
+    }
+}
@@ -0,0 +1,459 @@
+use crate::{
+    line_sensor::{LINE_SENSOR_COUNT, LINE_SENSOR_MAX, LineCalibration, LineState},
+    motion_sensor::Acceleration,
+    motor::{Motion, MotorCommand, MotorError, Motors, StopMode},
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal, watch::Watch};
+use embassy_time::{Duration, Instant, Timer};
+
+// Straight to the motors task, ahead of anything waiting in MOTORS_CHANNEL
+pub static SAFETY_STOP: Signal<ThreadModeRawMutex, SafetyEvent> = Signal::new();
+// The event the car is stopped for until Ok is pressed on the remote, if any
+pub static SAFETY_HOLD: Watch<ThreadModeRawMutex, Option<SafetyEvent>, 3> = Watch::new_with(None);
+// Set by the line modes while they drive over a pad or a junction, which look as dark
+// as no floor at all
+static DARK_FLOOR_EXPECTED: AtomicBool = AtomicBool::new(false);
+
+// Darker than any tape: nothing under the sensor reflects the light back
+const CLIFF_LEVEL: u16 = LINE_SENSOR_MAX - LINE_SENSOR_MAX / 32;
+// Digital sensors read a black patch the same as no floor at all, so on those boards
+// the darkness has to last longer than driving over a junction before it counts
+#[cfg(feature = "line-sensor-saadc")]
+const CLIFF_CONFIRM: Duration = Duration::from_millis(20);
+#[cfg(not(feature = "line-sensor-saadc"))]
+const CLIFF_CONFIRM: Duration = Duration::from_millis(120);
+const CLIFF_BACKOFF_SPEED: i16 = 0x90;
+const CLIFF_BACKOFF: Duration = Duration::from_millis(400);
+
+// Change between two samples 20ms apart, driving over bumps stays well below
+const IMPACT_JERK_MG: i32 = 1500;
+// Falling or being lifted quickly, the sensor feels next to no gravity
+const FREE_FALL_MG: i32 = 300;
+const FREE_FALL_CONFIRM: Duration = Duration::from_millis(60);
+// Gravity more than 60° away from where it was at rest, where cos² drops below 1/4
+const TIP_OVER_COS_SQUARED_DIV: i64 = 4;
+const TIP_OVER_CONFIRM: Duration = Duration::from_millis(500);
+// Still enough to take as the upright position
+const UPRIGHT_TOLERANCE_MG: i32 = 100;
+
+// Rolling wheels shake the car, a stalled one only feels the sensor noise. Wheels
+// spinning in the air probably shake it as well, so a lifted car is likely missed.
+// Average change between samples, summed over the axes
+const STALL_ACTIVITY_MG: i32 = 40;
+// Samples the activity is averaged over
+const STALL_AVERAGE: i32 = 8;
+const STALL_CONFIRM: Duration = Duration::from_millis(1500);
+const STALL_BACKOFF: Duration = Duration::from_millis(300);
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum SafetyEvent {
+    // The floor is gone under the front of the car
+    Cliff,
+    // Hit something, or got hit
+    Impact,
+    // Lifted or dropped
+    FreeFall,
+    // On its side or on its back
+    TippedOver,
+    // Driven but not moving, backing off lets an autonomous mode try again
+    Stall { back_off: bool },
+}
+
+impl SafetyEvent {
+    // Nothing drives the car after these until somebody had a look at it
+    pub fn needs_resume(&self) -> bool {
+        matches!(
+            self,
+            SafetyEvent::Impact | SafetyEvent::FreeFall | SafetyEvent::TippedOver
+        )
+    }
+
+    pub fn hold(self) {
+        SAFETY_HOLD.sender().send(Some(self));
+    }
+
+    pub fn held() -> Option<Self> {
+        SAFETY_HOLD.try_get().flatten()
+    }
+
+    pub fn resume() {
+        SAFETY_HOLD.sender().send(None);
+    }
+
+    // Runs in the motors task, so nothing else can drive the motors meanwhile
+    pub async fn respond(&self, motors: &mut Motors) -> Result<(), MotorError> {
+        let stalled = motors.state().motion();
+        motors.emergency_stop().await?;
+        match self {
+            SafetyEvent::Cliff => {
+                let motion = Motion {
+                    forward: -CLIFF_BACKOFF_SPEED,
+                    strafe: 0,
+                    turn: 0,
+                };
+                MotorCommand::Drive(motion).execute(motors).await?;
+                Timer::after(CLIFF_BACKOFF).await;
+                MotorCommand::Stop(StopMode::BrakeThenCoast)
+                    .execute(motors)
+                    .await
+            }
+            // Undo a little of what got the car stuck
+            SafetyEvent::Stall { back_off: true } => {
+                let motion = Motion {
+                    forward: -stalled.forward,
+                    strafe: -stalled.strafe,
+                    turn: -stalled.turn,
+                };
+                MotorCommand::Drive(motion).execute(motors).await?;
+                Timer::after(STALL_BACKOFF).await;
+                MotorCommand::Stop(StopMode::BrakeThenCoast)
+                    .execute(motors)
+                    .await
+            }
+            SafetyEvent::Stall { back_off: false } => {
+                MotorCommand::Stop(StopMode::BrakeThenCoast)
+                    .execute(motors)
+                    .await
+            }
+            // Keep braking, the wheels may be in the air
+            SafetyEvent::Impact | SafetyEvent::FreeFall | SafetyEvent::TippedOver => Ok(()),
+        }
+    }
+}
+
+pub fn expect_dark_floor(expected: bool) {
+    DARK_FLOOR_EXPECTED.store(expected, Ordering::Relaxed);
+}
+
+pub fn dark_floor_expected() -> bool {
+    DARK_FLOOR_EXPECTED.load(Ordering::Relaxed)
+}
+
+#[derive(Default)]
+pub struct CliffDetector {
+    dark_since: Option<Instant>,
+    triggered: bool,
+}
+
+impl CliffDetector {
+    // True once per edge, the floor has to come back before it triggers again
+    pub fn update(
+        &mut self,
+        line: &LineState,
+        calibration: &LineCalibration,
+        now: Instant,
+    ) -> bool {
+        let no_floor =
+            (0..LINE_SENSOR_COUNT).all(|i| calibration.normalize(i, line.raw[i]) >= CLIFF_LEVEL);
+        if !no_floor {
+            self.dark_since = None;
+            self.triggered = false;
+            return false;
+        }
+
+        let dark_since = *self.dark_since.get_or_insert(now);
+        if !self.triggered && now - dark_since >= CLIFF_CONFIRM {
+            self.triggered = true;
+            return true;
+        }
+        false
+    }
+}
+
+// Watches the accelerometer for the car being hit, lifted or tipped over
+#[derive(Default)]
+pub struct CrashDetector {
+    last: Option<Acceleration>,
+    // Gravity while the car stood upright, learnt from the first still samples
+    upright: Option<Acceleration>,
+    light_since: Option<Instant>,
+    tilted_since: Option<Instant>,
+}
+
+impl CrashDetector {
+    pub fn update(&mut self, sample: Acceleration, now: Instant) -> Option<SafetyEvent> {
+        let last = self.last.replace(sample);
+
+        if let Some(last) = last {
+            let jerk = length_squared(&Acceleration {
+                x: sample.x - last.x,
+                y: sample.y - last.y,
+                z: sample.z - last.z,
+            });
+            if jerk > IMPACT_JERK_MG * IMPACT_JERK_MG {
+                return Some(SafetyEvent::Impact);
+            }
+            if self.upright.is_none() && jerk < UPRIGHT_TOLERANCE_MG * UPRIGHT_TOLERANCE_MG {
+                self.upright = Some(sample);
+            }
+        }
+
+        let gravity = length_squared(&sample);
+        if gravity < FREE_FALL_MG * FREE_FALL_MG {
+            let light_since = *self.light_since.get_or_insert(now);
+            if now - light_since >= FREE_FALL_CONFIRM {
+                return Some(SafetyEvent::FreeFall);
+            }
+        } else {
+            self.light_since = None;
+        }
+
+        if let Some(upright) = self.upright {
+            let dot = sample.x as i64 * upright.x as i64
+                + sample.y as i64 * upright.y as i64
+                + sample.z as i64 * upright.z as i64;
+            // cos = dot / (|sample| |upright|), squared so there's no root to take
+            let tilted = dot <= 0
+                || dot * dot * TIP_OVER_COS_SQUARED_DIV
+                    < gravity as i64 * length_squared(&upright) as i64;
+            if tilted {
+                let tilted_since = *self.tilted_since.get_or_insert(now);
+                if now - tilted_since >= TIP_OVER_CONFIRM {
+                    return Some(SafetyEvent::TippedOver);
+                }
+            } else {
+                self.tilted_since = None;
+            }
+        }
+        None
+    }
+
+    // Back on its wheels, the upright position stays as it was learnt
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.light_since = None;
+        self.tilted_since = None;
+    }
+}
+
+// Compares what the wheels are told with what the accelerometer feels
+#[derive(Default)]
+pub struct StallDetector {
+    last: Option<Acceleration>,
+    activity: i32,
+    quiet_since: Option<Instant>,
+    triggered: bool,
+}
+
+impl StallDetector {
+    // True once per stall, the car has to stop or move again before it triggers again
+    pub fn update(&mut self, sample: Acceleration, driven: bool, now: Instant) -> bool {
+        if let Some(last) = self.last.replace(sample) {
+            let change = (sample.x as i32 - last.x as i32).abs()
+                + (sample.y as i32 - last.y as i32).abs()
+                + (sample.z as i32 - last.z as i32).abs();
+            self.activity += (change - self.activity) / STALL_AVERAGE;
+        }
+        if !driven || self.activity >= STALL_ACTIVITY_MG {
+            self.quiet_since = None;
+            self.triggered = false;
+            return false;
+        }
+
+        let quiet_since = *self.quiet_since.get_or_insert(now);
+        if !self.triggered && now - quiet_since >= STALL_CONFIRM {
+            self.triggered = true;
+            return true;
+        }
+        false
+    }
+}
+
+fn length_squared(acceleration: &Acceleration) -> i32 {
+    let Acceleration { x, y, z } = *acceleration;
+    x as i32 * x as i32 + y as i32 * y as i32 + z as i32 * z as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The motion sensor task samples every 20ms
+    const SAMPLE_MS: u64 = 20;
+    const FLAT: Acceleration = Acceleration {
+        x: 0,
+        y: 0,
+        z: 1000,
+    };
+
+    fn feed(
+        detector: &mut CrashDetector,
+        sample: Acceleration,
+        from_ms: u64,
+        until_ms: u64,
+    ) -> Option<SafetyEvent> {
+        (from_ms..until_ms)
+            .step_by(SAMPLE_MS as usize)
+            .find_map(|ms| detector.update(sample, Instant::from_millis(ms)))
+    }
+
+    #[test]
+    fn crash_detector_keeps_quiet_while_standing() {
+        let mut detector = CrashDetector::default();
+        assert!(feed(&mut detector, FLAT, 0, 2000).is_none());
+    }
+
+    #[test]
+    fn crash_detector_sees_an_impact() {
+        let mut detector = CrashDetector::default();
+        feed(&mut detector, FLAT, 0, 200);
+        let hit = Acceleration {
+            x: 1800,
+            y: 0,
+            z: 1000,
+        };
+        let event = detector.update(hit, Instant::from_millis(200));
+        assert!(matches!(event, Some(SafetyEvent::Impact)));
+    }
+
+    #[test]
+    fn crash_detector_sees_a_fall_once_it_lasts() {
+        let mut detector = CrashDetector::default();
+        feed(&mut detector, FLAT, 0, 200);
+        // Falling into weightlessness takes a few samples, each a small change
+        let falling = [700, 400, 100].map(|z| Acceleration { x: 0, y: 0, z });
+        for (i, sample) in falling.into_iter().enumerate() {
+            assert!(
+                detector
+                    .update(sample, Instant::from_millis(200 + i as u64 * SAMPLE_MS))
+                    .is_none()
+            );
+        }
+        let weightless = Acceleration { x: 0, y: 0, z: 100 };
+        assert!(feed(&mut detector, weightless, 260, 300).is_none());
+        let event = feed(&mut detector, weightless, 300, 400);
+        assert!(matches!(event, Some(SafetyEvent::FreeFall)));
+    }
+
+    #[test]
+    fn crash_detector_sees_a_tip_over_once_it_lasts() {
+        let mut detector = CrashDetector::default();
+        feed(&mut detector, FLAT, 0, 200);
+        let on_its_side = Acceleration {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        assert!(feed(&mut detector, on_its_side, 200, 680).is_none());
+        let event = feed(&mut detector, on_its_side, 680, 800);
+        assert!(matches!(event, Some(SafetyEvent::TippedOver)));
+    }
+
+    #[test]
+    fn crash_detector_lets_a_slope_pass() {
+        let mut detector = CrashDetector::default();
+        feed(&mut detector, FLAT, 0, 200);
+        // About 30° up a ramp
+        let slope = Acceleration {
+            x: 500,
+            y: 0,
+            z: 866,
+        };
+        assert!(feed(&mut detector, slope, 200, 2000).is_none());
+    }
+
+    // Times the stall detector triggers, with every other sample moved by `shake`
+    fn stalls(
+        detector: &mut StallDetector,
+        shake: i16,
+        driven: bool,
+        from_ms: u64,
+        until_ms: u64,
+    ) -> usize {
+        (from_ms..until_ms)
+            .step_by(SAMPLE_MS as usize)
+            .enumerate()
+            .filter(|&(i, ms)| {
+                let sample = Acceleration {
+                    x: if i % 2 == 0 { shake } else { 0 },
+                    ..FLAT
+                };
+                detector.update(sample, driven, Instant::from_millis(ms))
+            })
+            .count()
+    }
+
+    #[test]
+    fn stall_detector_triggers_once_for_a_driven_car_that_feels_nothing() {
+        let mut detector = StallDetector::default();
+        assert_eq!(stalls(&mut detector, 0, true, 0, 1400), 0);
+        let mut detector = StallDetector::default();
+        assert_eq!(stalls(&mut detector, 0, true, 0, 5000), 1);
+    }
+
+    #[test]
+    fn stall_detector_lets_a_rolling_car_pass() {
+        let mut detector = StallDetector::default();
+        assert_eq!(stalls(&mut detector, 100, true, 0, 5000), 0);
+    }
+
+    #[test]
+    fn stall_detector_lets_a_standing_car_pass() {
+        let mut detector = StallDetector::default();
+        assert_eq!(stalls(&mut detector, 0, false, 0, 5000), 0);
+    }
+
+    #[test]
+    fn stall_detector_triggers_again_after_the_car_stopped() {
+        let mut detector = StallDetector::default();
+        assert_eq!(stalls(&mut detector, 0, true, 0, 2000), 1);
+        assert!(!detector.update(FLAT, false, Instant::from_millis(2000)));
+        assert_eq!(stalls(&mut detector, 0, true, 2020, 4000), 1);
+    }
+
+    // Line sensor samples are 10ms apart
+    const LINE_SAMPLE_MS: u64 = 10;
+    const NO_FLOOR: [u16; LINE_SENSOR_COUNT] = [LINE_SENSOR_MAX; LINE_SENSOR_COUNT];
+    const FLOOR: [u16; LINE_SENSOR_COUNT] = [0; LINE_SENSOR_COUNT];
+
+    // Times the cliff detector triggers while reading `raw`
+    fn cliffs(
+        detector: &mut CliffDetector,
+        raw: [u16; LINE_SENSOR_COUNT],
+        from_ms: u64,
+        until_ms: u64,
+    ) -> usize {
+        let calibration = LineCalibration::DEFAULT;
+        let line = calibration.state(raw);
+        (from_ms..until_ms)
+            .step_by(LINE_SAMPLE_MS as usize)
+            .filter(|&ms| detector.update(&line, &calibration, Instant::from_millis(ms)))
+            .count()
+    }
+
+    #[test]
+    fn cliff_detector_triggers_once_the_floor_is_gone_long_enough() {
+        let confirm_ms = CLIFF_CONFIRM.as_millis();
+        let mut detector = CliffDetector::default();
+        assert_eq!(cliffs(&mut detector, FLOOR, 0, 500), 0);
+        assert_eq!(cliffs(&mut detector, NO_FLOOR, 500, 500 + confirm_ms), 0);
+        // Only once for as long as the edge lasts
+        assert_eq!(cliffs(&mut detector, NO_FLOOR, 500 + confirm_ms, 3000), 1);
+    }
+
+    #[test]
+    fn cliff_detector_ignores_short_dark_patches() {
+        let confirm_ms = CLIFF_CONFIRM.as_millis();
+        let mut detector = CliffDetector::default();
+        for start_ms in (0..3000).step_by(confirm_ms as usize) {
+            let floor_ms = start_ms + confirm_ms - LINE_SAMPLE_MS;
+            assert_eq!(cliffs(&mut detector, NO_FLOOR, start_ms, floor_ms), 0);
+            assert_eq!(
+                cliffs(&mut detector, FLOOR, floor_ms, start_ms + confirm_ms),
+                0
+            );
+        }
+        // A line or junction under some of the sensors is no edge, however long
+        let line = [LINE_SENSOR_MAX, LINE_SENSOR_MAX, 0];
+        assert_eq!(cliffs(&mut detector, line, 3000, 6000), 0);
+    }
+
+    #[test]
+    fn cliff_detector_triggers_again_once_the_floor_came_back() {
+        let mut detector = CliffDetector::default();
+        assert_eq!(cliffs(&mut detector, NO_FLOOR, 0, 1000), 1);
+        assert_eq!(cliffs(&mut detector, FLOOR, 1000, 1010), 0);
+        assert_eq!(cliffs(&mut detector, NO_FLOOR, 1010, 2000), 1);
+    }
+}
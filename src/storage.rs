@@ -0,0 +1,135 @@
+use crate::recorder::RECORDING_COUNT;
+use core::cell::RefCell;
+use defmt::warn;
+use embassy_nrf::{
+    Peri,
+    nvmc::{Nvmc, PAGE_SIZE},
+    peripherals::NVMC,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::ThreadModeRawMutex};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+// Settings live in the last pages of the flash, memory.x keeps the program out of them
+const SETTINGS_START: u32 = 0x78000;
+// The calibrations that were there before the recordings keep their pages at 0x7C000
+const FIRST_CALIBRATION_INDEX: u32 = 4;
+const RECORD_MAGIC: u32 = 0x5243_5354; // "RCST"
+const HEADER_SIZE: usize = 8;
+pub const MAX_RECORD_SIZE: usize = 1024;
+
+static STORAGE: Mutex<ThreadModeRawMutex, RefCell<Option<Nvmc<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+// One flash page for each kind of record
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Slot {
+    LineCalibration,
+    WheelCalibration,
+    // Driving recordings, one for each number key they are assigned to
+    Recording(u8),
+    CompassCalibration,
+}
+
+impl Slot {
+    // The newer slots fill the pages below the calibrations
+    const fn index(self) -> u32 {
+        const {
+            assert!((RECORDING_COUNT as u32) < FIRST_CALIBRATION_INDEX);
+        }
+        match self {
+            Slot::Recording(n) => n as u32,
+            Slot::CompassCalibration => RECORDING_COUNT as u32,
+            Slot::LineCalibration => FIRST_CALIBRATION_INDEX,
+            Slot::WheelCalibration => FIRST_CALIBRATION_INDEX + 1,
+        }
+    }
+
+    const fn address(self) -> u32 {
+        SETTINGS_START + self.index() * PAGE_SIZE as u32
+    }
+}
+
+pub trait Record: Sized {
+    // Where load and save keep it, records of the same kind in several slots
+    // go through load_from and save_to instead
+    const SLOT: Slot;
+    // Encoded size in bytes, at most MAX_RECORD_SIZE
+    const SIZE: usize;
+
+    fn encode(&self, buf: &mut [u8]);
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+#[derive(Clone, Copy, defmt::Format)]
+pub enum StorageError {
+    NotInitialized,
+    Flash,
+}
+
+pub fn init(p_nvmc: Peri<'static, NVMC>) {
+    STORAGE.lock(|storage| storage.replace(Some(Nvmc::new(p_nvmc))));
+}
+
+pub fn load<T: Record>() -> Option<T> {
+    load_from(T::SLOT)
+}
+
+// None if the slot was never written, or holds something else
+pub fn load_from<T: Record>(slot: Slot) -> Option<T> {
+    STORAGE.lock(|storage| {
+        let mut storage = storage.borrow_mut();
+        let nvmc = storage.as_mut()?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        nvmc.read(slot.address(), &mut header).ok()?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let size = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let checksum = u16::from_le_bytes([header[6], header[7]]);
+        if magic != RECORD_MAGIC || size != T::SIZE || size > MAX_RECORD_SIZE {
+            return None;
+        }
+
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        nvmc.read(slot.address() + HEADER_SIZE as u32, &mut buf[..size])
+            .ok()?;
+        if checksum != fletcher16(&buf[..size]) {
+            warn!("Corrupted record in slot {}", slot);
+            return None;
+        }
+        T::decode(&buf[..size])
+    })
+}
+
+pub fn save<T: Record>(record: &T) -> Result<(), StorageError> {
+    save_to(T::SLOT, record)
+}
+
+// Blocks until the page is erased and written, tens of milliseconds
+pub fn save_to<T: Record>(slot: Slot, record: &T) -> Result<(), StorageError> {
+    let mut buf = [0u8; HEADER_SIZE + MAX_RECORD_SIZE];
+    let (header, payload) = buf.split_at_mut(HEADER_SIZE);
+    record.encode(&mut payload[..T::SIZE]);
+    header[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&(T::SIZE as u16).to_le_bytes());
+    header[6..8].copy_from_slice(&fletcher16(&payload[..T::SIZE]).to_le_bytes());
+    // Flash is written in whole words
+    let len = (HEADER_SIZE + T::SIZE).next_multiple_of(4);
+
+    STORAGE.lock(|storage| {
+        let mut storage = storage.borrow_mut();
+        let nvmc = storage.as_mut().ok_or(StorageError::NotInitialized)?;
+        let address = slot.address();
+        nvmc.erase(address, address + PAGE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)?;
+        nvmc.write(address, &buf[..len])
+            .map_err(|_| StorageError::Flash)
+    })
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (sum1, sum2) = data.iter().fold((0u16, 0u16), |(sum1, sum2), &byte| {
+        let sum1 = (sum1 + byte as u16) % 255;
+        (sum1, (sum2 + sum1) % 255)
+    });
+    (sum2 << 8) | sum1
+}
@@ -1,65 +1,198 @@
 use crate::{
     big_led::{BIG_LEDS_CHANNEL, BigLed, BigLedCommand},
     bottom_led::BOTTOM_LEDS_CHANNEL,
+    compass::{self, COMPASS_CALIBRATION, CompassCalibration, CompassCalibrator, HEADING},
+    display::{DISPLAY_CHANNEL, DisplayCommand, Icon, Image},
+    expander::{Expander, Wheel},
     ir_remote_control::{IrButton, IrDecodeResult, IrRemoteController, decode_nec},
-    motor::{MOTORS_CHANNEL, Motor, MotorPower},
+    line_follow::{FollowStep, LINE_FOLLOW_TUNING, LineFollowConfig, LineFollower},
+    line_sensor::{LINE_CALIBRATION, LINE_STATE, LineCalibration, LineCalibrator},
+    math::{atan2, hypot, wrap_angle},
+    maze::{Junction, JunctionDetector, MazeError, MazeEvent, MazeRoute, Turn},
+    mode::{MODE, Mode},
+    motion_sensor::{ACCELERATION, Irqs as MotionSensorIrqs, Lsm303agr, MAGNETIC_FIELD},
+    motor::{
+        Direction, MOTION_STATE, MOTORS_CHANNEL, Manoeuvre, ManoeuvreEnd, ManoeuvreReply, Motion,
+        Motor, MotorCommand, MotorPower, Motors, Rotation, StopMode, WHEEL_CALIBRATION,
+        WHEEL_TRIM_CHANNEL, WheelCalibration, WheelTrimCommand,
+    },
+    odometry::{self, Pose},
+    recorder::{Action, RECORDER_CHANNEL, RecorderEvent, Recording},
+    safety::{
+        self, CliffDetector, CrashDetector, SAFETY_HOLD, SAFETY_STOP, SafetyEvent, StallDetector,
+    },
     servo::ServoDirection,
-    twim::{Irqs, TWIN_CHANNEL},
+    storage::{self, Slot},
+    twim::{Irqs, RECOVERY_THRESHOLD, RegisterShadow, TWIN_LANES, TWIN_STATS, recover_bus},
 };
-use defmt::debug;
+use core::f32::consts::PI;
+use defmt::{debug, info, warn};
+use embassy_futures::select::{Either, Either3, select, select3};
 use embassy_nrf::{
     Peri,
     gpio::{Input, Pull},
-    peripherals::{P0_01, P0_02, P0_11, P0_26, P1_00, PWM0, PWM1, TWISPI0},
-    pwm::{
-        Prescaler, SequenceConfig, SequenceLoad, SequencePwm, SimplePwm, SingleSequenceMode,
-        SingleSequencer,
+    peripherals::{
+        P0_01, P0_02, P0_03, P0_04, P0_08, P0_16, P0_25, P0_26, P1_00, PWM1, TWISPI0, TWISPI1,
     },
+    pwm::{Prescaler, SimplePwm},
     twim::Twim,
 };
-use embassy_time::{Duration, Instant, Timer};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_timeout};
 use static_cell::ConstStaticCell;
 
+#[cfg(feature = "full-matrix")]
+use crate::led_matrix::{Content, Display};
+#[cfg(not(feature = "line-sensor-saadc"))]
+use crate::line_sensor::LINE_SENSOR_MAX;
+#[cfg(feature = "line-sensor-saadc")]
+use crate::line_sensor::{Irqs as SaadcIrqs, LINE_SENSOR_COUNT};
+#[cfg(feature = "full-matrix")]
+use embassy_nrf::gpio::AnyPin;
+#[cfg(not(feature = "line-sensor-saadc"))]
+use embassy_nrf::peripherals::P0_10;
+#[cfg(not(feature = "full-matrix"))]
+use embassy_nrf::{
+    peripherals::{P0_11, PWM0},
+    pwm::{SequenceConfig, SequenceLoad, SequencePwm, SingleSequenceMode, SingleSequencer},
+};
+#[cfg(feature = "line-sensor-saadc")]
+use embassy_nrf::{
+    peripherals::{P0_31, SAADC},
+    saadc::{ChannelConfig, Saadc},
+};
+
 // Low-level constants for WS2812B LED control
+#[cfg(not(feature = "full-matrix"))]
 const T1H: u16 = 0x8000 | 13; // Duty = 13/20 ticks (0.8us/1.25us) for a 1
+#[cfg(not(feature = "full-matrix"))]
 const T0H: u16 = 0x8000 | 7; // Duty 7/20 ticks (0.4us/1.25us) for a 0
+#[cfg(not(feature = "full-matrix"))]
 const RES: u16 = 0x8000;
 
+// How often the TWIN bus counters go to the log
+const TWIN_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
 // IR remote control constants
 const NEC_REPEAT_HIGH_MIN: u32 = 2000;
 const TIMINGS_SIZE: usize = 120;
 const PULSE_TIMEOUT_US: u32 = 18000;
 const SAMPLE_INTERVAL_US: u64 = 15;
 
+// Line tracking sensor constants
+const LINE_SAMPLE_INTERVAL_MS: u64 = 5;
+const LINE_FOLLOW_INTERVAL_MS: u64 = 20;
+const LINE_CALIBRATION_TURN: i16 = 0x80;
+const LINE_CALIBRATION_SWEEP_MS: u64 = 1200;
+static LINE_CALIBRATION_MANOEUVRES: ManoeuvreReply = ManoeuvreReply::new();
+
+// Maze solving constants
+const MAZE_EXPLORE_SPEED: i16 = 0x80;
+const MAZE_SPEED_RUN_SPEED: i16 = 0xC0;
+const MAZE_TURN_SPEED: i16 = 0x90;
+const MAZE_INCH_MS: u64 = 150;
+const MAZE_TURN_TIMEOUT_MS: u64 = 3000;
+static MAZE_MANOEUVRES: ManoeuvreReply = ManoeuvreReply::new();
+
+// Motion sensor constants
+const MOTION_SENSOR_SAMPLE_MS: u64 = 20;
+// The interrupt line is shared, so the samples are also looked for without it now and then
+const MOTION_SENSOR_POLL_MS: u64 = 100;
+const MOTION_SENSOR_RETRY_MS: u64 = 1000;
+
+// Stall guard constants
+// Stalls in a row an autonomous mode gets to back off from, before it is stopped
+const STALL_MAX_RETRIES: u8 = 3;
+// A stall later than this after the one before starts the count again
+const STALL_RETRY_WINDOW: Duration = Duration::from_secs(10);
+
+// Compass calibration constants
+const COMPASS_CALIBRATION_TURN: i16 = 0x60;
+const COMPASS_CALIBRATION_ROTATIONS: f32 = 3.0;
+static COMPASS_CALIBRATION_MANOEUVRES: ManoeuvreReply = ManoeuvreReply::new();
+
+// Return to start constants
+const RETURN_SPEED: i16 = 0x80;
+const RETURN_TURN_SPEED: i16 = 0x90;
+// Closer than this the car only turns back to the starting direction
+const RETURN_CLOSE_ENOUGH_MM: f32 = 50.0;
+const RETURN_TURN_TIMEOUT: Duration = Duration::from_secs(5);
+static RETURN_MANOEUVRES: ManoeuvreReply = ManoeuvreReply::new();
+
 // This allows the under-leds and the motors to work
 #[embassy_executor::task]
 pub async fn twin_task(
-    p_twin: Peri<'static, TWISPI0>,
-    p_i2c_ext_sda: Peri<'static, P1_00>,
-    p_i2c_ext_scl: Peri<'static, P0_26>,
+    mut p_twin: Peri<'static, TWISPI0>,
+    mut p_i2c_ext_sda: Peri<'static, P1_00>,
+    mut p_i2c_ext_scl: Peri<'static, P0_26>,
 ) {
-    let config = embassy_nrf::twim::Config::default();
-
     static RAM_BUFFER: ConstStaticCell<[u8; 16]> = ConstStaticCell::new([0; 16]);
-    let mut twi = Twim::new(
-        p_twin,
-        Irqs,
-        p_i2c_ext_sda,
-        p_i2c_ext_scl,
-        config,
-        RAM_BUFFER.take(),
-    );
+    let ram_buffer = RAM_BUFFER.take();
+    let mut shadow = RegisterShadow::new();
+    let mut booted = false;
+
+    loop {
+        // The driver is rebuilt on top of the same pins after every bus recovery
+        let mut expander = Expander::new(Twim::new(
+            p_twin.reborrow(),
+            Irqs,
+            p_i2c_ext_sda.reborrow(),
+            p_i2c_ext_scl.reborrow(),
+            embassy_nrf::twim::Config::default(),
+            ram_buffer,
+        ));
+        let result = if booted {
+            shadow.resync(&mut expander).await
+        } else {
+            shadow.init(&mut expander).await
+        };
+        booted = true;
+        if let Err(error) = result {
+            warn!("TWIN init failed: {}", error);
+            // Usually the car is switched off and the micro:bit runs from USB
+            let _ = DISPLAY_CHANNEL.try_send(DisplayCommand::Icon(Icon::Battery));
+        }
+
+        let mut failures = 0;
+        while failures < RECOVERY_THRESHOLD {
+            let command = TWIN_LANES.receive().await;
+            let result = shadow.transfer(&mut expander, command.request()).await;
+            match result {
+                Ok(_) => failures = 0,
+                Err(error) => {
+                    warn!("TWIN request failed: {}", error);
+                    failures += 1;
+                }
+            }
+            command.reply(result);
+        }
 
+        warn!("TWIN bus stuck, recovering");
+        drop(expander);
+        recover_bus(p_i2c_ext_sda.reborrow(), p_i2c_ext_scl.reborrow()).await;
+    }
+}
+
+// Counters of the TWIN bus, to tell a flaky wire from a dead expander in the log
+#[embassy_executor::task]
+pub async fn twin_stats() {
+    let mut ticker = Ticker::every(TWIN_STATS_INTERVAL);
     loop {
-        let command = TWIN_CHANNEL.receive().await;
-        let _ = twi.blocking_write(0x30, &mut [command.channel(), command.value()]);
+        ticker.next().await;
+        info!("TWIN: {}", TWIN_STATS);
     }
 }
 
 #[embassy_executor::task]
 pub async fn big_leds() {
+    // Pick up where the LEDs were left, they keep their value over a reset of the micro:bit
+    let mut big_led_state = match BigLed::all_leds()[0].read_value().await {
+        Ok(value) => value,
+        Err(error) => {
+            warn!("Big LED read failed: {}", error);
+            0x00
+        }
+    };
     debug!("Big LEDs initialized");
-    let mut big_led_state = 0x00u8;
     loop {
         match BIG_LEDS_CHANNEL.receive().await {
             BigLedCommand::Toggle => {
@@ -67,13 +200,24 @@ pub async fn big_leds() {
                 big_led_state = if big_led_state == 0x00 { 0xFF } else { 0x00 };
                 // Set all LEDs to the new state sequentially
                 for mut led in BigLed::all_leds().into_iter() {
-                    led.set_value(big_led_state).await;
+                    if let Err(error) = led.set_value(big_led_state).await {
+                        warn!("Big LED write failed: {}", error);
+                    }
+                }
+            }
+            BigLedCommand::Brightness(value) => {
+                big_led_state = value;
+                for mut led in BigLed::all_leds().into_iter() {
+                    if let Err(error) = led.set_value(big_led_state).await {
+                        warn!("Big LED write failed: {}", error);
+                    }
                 }
             }
         }
     }
 }
 
+#[cfg(not(feature = "full-matrix"))]
 #[embassy_executor::task]
 pub async fn bottom_leds(p_pwm: Peri<'static, PWM0>, p: Peri<'static, P0_11>) {
     debug!("Bottom LEDs initialized");
@@ -176,17 +320,92 @@ pub async fn servo(p_pwm1: Peri<'static, PWM1>, p: Peri<'static, P0_01>) {
 
 #[embassy_executor::task]
 pub async fn motors() {
+    let mut motors = Motors::new();
+    let mut next = None;
     loop {
-        MOTORS_CHANNEL.receive().await.execute().await;
+        // Safety stops win over queued commands
+        let command = match next.take() {
+            Some(command) => command,
+            None => match select(SAFETY_STOP.wait(), MOTORS_CHANNEL.receive()).await {
+                Either::First(event) => {
+                    safety_stop(&mut motors, event).await;
+                    continue;
+                }
+                Either::Second(command) => command,
+            },
+        };
+
+        // A safety stop cuts short the running command, and so does any newer command
+        let end = match select3(
+            SAFETY_STOP.wait(),
+            command.execute_holding_heading(&mut motors),
+            MOTORS_CHANNEL.receive(),
+        )
+        .await
+        {
+            // After backing off a stall, whoever waits can try the same again
+            Either3::First(event @ SafetyEvent::Stall { back_off: true }) => {
+                safety_stop(&mut motors, event).await;
+                command.report(ManoeuvreEnd::Stalled);
+                continue;
+            }
+            Either3::First(event) => {
+                command.report(ManoeuvreEnd::Cancelled);
+                safety_stop(&mut motors, event).await;
+                continue;
+            }
+            Either3::Second(Ok(())) => ManoeuvreEnd::Completed,
+            Either3::Second(Err(error)) => {
+                warn!("Motor command failed: {}", error);
+                ManoeuvreEnd::Failed(error)
+            }
+            Either3::Third(newer) => {
+                next = Some(newer);
+                ManoeuvreEnd::Cancelled
+            }
+        };
+        command.report(end);
+    }
+}
+
+async fn safety_stop(motors: &mut Motors, event: SafetyEvent) {
+    warn!(
+        "Safety stop: {} while {}",
+        event,
+        motors.state().direction()
+    );
+    if let Err(error) = event.respond(motors).await {
+        warn!("Safety stop failed: {}", error);
+    }
+    // Anything queued before the stop is stale by now
+    while let Ok(command) = MOTORS_CHANNEL.try_receive() {
+        command.report(ManoeuvreEnd::Cancelled);
+    }
+
+    if event.needs_resume() {
+        // Nothing moves the car until the remote says so, manoeuvres hear they were cancelled
+        let mut hold = SAFETY_HOLD.receiver().unwrap();
+        while let Either::Second(command) = select(
+            hold.get_and(|held| held.is_none()),
+            MOTORS_CHANNEL.receive(),
+        )
+        .await
+        {
+            command.report(ManoeuvreEnd::Cancelled);
+        }
+        // The car may have been picked up meanwhile, whatever the guards saw is stale
+        SAFETY_STOP.reset();
+        info!("Resumed after {}", event);
     }
 }
 
 #[embassy_executor::task]
 pub async fn ir_remote_control(p: Peri<'static, P0_02>) {
     let mut ir_pin = Input::new(p, Pull::Up);
-    let mut controller = IrRemoteController;
+    let mut controller = IrRemoteController::new();
     debug!("IR Remote Control initialized");
 
+    // No pause between captures, every repeat of a held button counts towards the hold
     loop {
         // Ensure line is idle before starting
         ir_pin.wait_for_high().await;
@@ -214,8 +433,11 @@ pub async fn ir_remote_control(p: Peri<'static, P0_02>) {
         }
 
         match decode_nec(&timings[..i]) {
-            IrDecodeResult::Button(button) => button.execute(&mut controller),
-            IrDecodeResult::Repeat => debug!("Button held (NEC repeat code)"),
+            IrDecodeResult::Button(button) => controller.press(button),
+            IrDecodeResult::Repeat => {
+                debug!("Button held (NEC repeat code)");
+                controller.repeat();
+            }
             IrDecodeResult::None => {
                 if i > 10 && timings[0] > NEC_REPEAT_HIGH_MIN {
                     debug!(
@@ -225,7 +447,958 @@ pub async fn ir_remote_control(p: Peri<'static, P0_02>) {
                 }
             }
         }
+    }
+}
+
+// Line tracking sensors on P1, P2 and P8 of the edge connector
+#[cfg(not(feature = "line-sensor-saadc"))]
+#[embassy_executor::task]
+pub async fn line_sensors(
+    p_left: Peri<'static, P0_03>,
+    p_center: Peri<'static, P0_04>,
+    p_right: Peri<'static, P0_10>,
+) {
+    let sensors = [
+        Input::new(p_left, Pull::None),
+        Input::new(p_center, Pull::None),
+        Input::new(p_right, Pull::None),
+    ];
+    let mut calibration_updates = LINE_CALIBRATION.receiver().unwrap();
+    let mut calibration = calibration_updates
+        .try_get()
+        .unwrap_or(LineCalibration::DEFAULT);
+    let line_state = LINE_STATE.sender();
+    debug!("Line sensors initialized");
+
+    loop {
+        if let Some(new_calibration) = calibration_updates.try_changed() {
+            calibration = new_calibration;
+        }
+        let raw = sensors
+            .each_ref()
+            .map(|sensor| if sensor.is_high() { LINE_SENSOR_MAX } else { 0 });
+        line_state.send(calibration.state(raw));
+
+        Timer::after_millis(LINE_SAMPLE_INTERVAL_MS).await;
+    }
+}
+
+// Analog line tracking sensors on P1, P2 and P3 of the edge connector
+#[cfg(feature = "line-sensor-saadc")]
+#[embassy_executor::task]
+pub async fn line_sensors(
+    p_saadc: Peri<'static, SAADC>,
+    p_left: Peri<'static, P0_03>,
+    p_center: Peri<'static, P0_04>,
+    p_right: Peri<'static, P0_31>,
+) {
+    let mut saadc = Saadc::new(
+        p_saadc,
+        SaadcIrqs,
+        embassy_nrf::saadc::Config::default(),
+        [
+            ChannelConfig::single_ended(p_left),
+            ChannelConfig::single_ended(p_center),
+            ChannelConfig::single_ended(p_right),
+        ],
+    );
+    saadc.calibrate().await;
+    let mut calibration_updates = LINE_CALIBRATION.receiver().unwrap();
+    let mut calibration = calibration_updates
+        .try_get()
+        .unwrap_or(LineCalibration::DEFAULT);
+    let line_state = LINE_STATE.sender();
+    debug!("Line sensors initialized");
+
+    let mut samples = [0i16; LINE_SENSOR_COUNT];
+    loop {
+        if let Some(new_calibration) = calibration_updates.try_changed() {
+            calibration = new_calibration;
+        }
+        saadc.sample(&mut samples).await;
+        // Single ended readings can dip slightly below zero
+        let raw = samples.map(|sample| sample.max(0) as u16);
+        line_state.send(calibration.state(raw));
+
+        Timer::after_millis(LINE_SAMPLE_INTERVAL_MS).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn line_follower() {
+    let mut mode = MODE.receiver().unwrap();
+    let mut follower = LineFollower::new(LineFollowConfig::DEFAULT);
+    debug!("Line follower initialized");
+
+    loop {
+        mode.get_and(|mode| *mode == Mode::LineFollow).await;
+        info!("Line following started");
+        follower.reset();
+
+        let result = select(mode.changed(), follow_line(&mut follower)).await;
+        stop_unless_taken_over(&result).await;
+        if let Either::Second(()) = result {
+            info!("Line lost, giving up");
+            Mode::set(Mode::Manual);
+        }
+    }
+}
+
+// Until the line is lost
+async fn follow_line(follower: &mut LineFollower) {
+    let mut ticker = Ticker::every(Duration::from_millis(LINE_FOLLOW_INTERVAL_MS));
+    loop {
+        ticker.next().await;
+        while let Ok(tuning) = LINE_FOLLOW_TUNING.try_receive() {
+            follower.tune(tuning);
+            info!("Line follower tuned: {}", follower.config());
+        }
+        let Some(line) = LINE_STATE.try_get() else {
+            continue;
+        };
+        match follower.step(&line, Instant::now()) {
+            FollowStep::Drive(motion) => {
+                MOTORS_CHANNEL.send(MotorCommand::Drive(motion)).await;
+            }
+            FollowStep::Lost => return,
+        }
+    }
+}
+
+// Brakes when a mode ends by itself. When another mode took over, that mode or the
+// remote drives the car now, and a late Stop would cut its first command short
+async fn stop_unless_taken_over<A, B>(result: &Either<A, B>) {
+    if let Either::Second(_) = result {
+        MOTORS_CHANNEL
+            .send(MotorCommand::Stop(StopMode::BrakeThenCoast))
+            .await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn line_calibration() {
+    let mut mode = MODE.receiver().unwrap();
+    debug!("Line calibration initialized");
+
+    loop {
+        mode.get_and(|mode| *mode == Mode::LineCalibration).await;
+        info!("Line calibration started, place the car over the line");
+
+        let result = select(mode.changed(), calibrate_line_sensors()).await;
+        stop_unless_taken_over(&result).await;
+
+        match result {
+            Either::First(_) => info!("Line calibration cancelled"),
+            Either::Second(Some(calibration)) => {
+                LINE_CALIBRATION.sender().send(calibration);
+                if let Err(error) = storage::save(&calibration) {
+                    warn!("Line calibration not saved: {}", error);
+                }
+                info!("Line calibration done: {}", calibration.thresholds);
+                DISPLAY_CHANNEL.send(DisplayCommand::Icon(Icon::Tick)).await;
+                blink_big_leds(1).await;
+                Mode::set(Mode::Manual);
+            }
+            Either::Second(None) => {
+                warn!("Line calibration failed, not enough contrast");
+                DISPLAY_CHANNEL
+                    .send(DisplayCommand::Icon(Icon::Cross))
+                    .await;
+                blink_big_leds(3).await;
+                Mode::set(Mode::Manual);
+            }
+        }
+        BIG_LEDS_CHANNEL.send(BigLedCommand::Brightness(0x00)).await;
+    }
+}
+
+// Spin right, all the way left and back to the start, sampling the sensors the whole time
+async fn calibrate_line_sensors() -> Option<LineCalibration> {
+    const SWEEPS: [(i16, u64); 3] = [(1, 1), (-1, 2), (1, 1)];
+    let total_ms: u64 =
+        SWEEPS.iter().map(|(_, sweeps)| sweeps).sum::<u64>() * LINE_CALIBRATION_SWEEP_MS;
+    let mut calibrator = LineCalibrator::new(
+        LINE_CALIBRATION
+            .try_get()
+            .unwrap_or(LineCalibration::DEFAULT),
+    );
+    let start = Instant::now();
+    let mut shown = None;
+
+    for (direction, sweeps) in SWEEPS {
+        let sweep = Manoeuvre::rotate(
+            direction * LINE_CALIBRATION_TURN,
+            Duration::from_millis(sweeps * LINE_CALIBRATION_SWEEP_MS),
+        );
+        let sample = async {
+            loop {
+                if let Some(line) = LINE_STATE.try_get() {
+                    calibrator.sample(&line.raw);
+                }
+                let progress = (Instant::now() - start).as_millis() * 0xFF / total_ms;
+                show_progress(progress.min(0xFF) as u8, &mut shown);
+                Timer::after_millis(LINE_SAMPLE_INTERVAL_MS).await;
+            }
+        };
+        select(sweep.run(&LINE_CALIBRATION_MANOEUVRES), sample).await;
+    }
+
+    calibrator.finish()
+}
+
+// Headlights get brighter and the display fills up as a calibration goes on
+fn show_progress(progress: u8, shown: &mut Option<Image>) {
+    let _ = BIG_LEDS_CHANNEL.try_send(BigLedCommand::Brightness(progress));
+    let image = Image::progress(progress);
+    if *shown != Some(image) {
+        *shown = Some(image);
+        let _ = DISPLAY_CHANNEL.try_send(DisplayCommand::Image(image));
+    }
+}
+
+async fn blink_big_leds(times: usize) {
+    for _ in 0..times {
+        BIG_LEDS_CHANNEL.send(BigLedCommand::Brightness(0xFF)).await;
+        Timer::after_millis(200).await;
+        BIG_LEDS_CHANNEL.send(BigLedCommand::Brightness(0x00)).await;
+        Timer::after_millis(200).await;
+    }
+}
+
+enum MazeRun<'a> {
+    // Learn the maze with the left hand rule, recording the route
+    Explore(&'a mut MazeRoute),
+    // Replay a recorded route as fast as possible
+    SpeedRun(&'a [Turn]),
+}
+
+#[embassy_executor::task]
+pub async fn maze_solver() {
+    let mut mode = MODE.receiver().unwrap();
+    let mut route = MazeRoute::new();
+    debug!("Maze solver initialized");
+
+    loop {
+        let selected = mode
+            .get_and(|mode| matches!(mode, Mode::MazeExplore | Mode::MazeSpeedRun))
+            .await;
+        let run = if selected == Mode::MazeExplore {
+            info!("Exploring the maze");
+            route = MazeRoute::new();
+            MazeRun::Explore(&mut route)
+        } else if route.is_empty() {
+            warn!("No maze route recorded, explore it first");
+            Mode::set(Mode::Manual);
+            continue;
+        } else {
+            info!("Speed run: {}", route.turns());
+            MazeRun::SpeedRun(route.turns())
+        };
+
+        let result = select(mode.changed(), solve_maze(run)).await;
+        safety::expect_dark_floor(false);
+        stop_unless_taken_over(&result).await;
+        // Only a route that made it to the goal is worth a speed run
+        let finished = matches!(result, Either::Second(Ok(())));
+        if selected == Mode::MazeExplore && !finished {
+            route = MazeRoute::new();
+        }
+
+        match result {
+            Either::First(_) => info!("Maze run cancelled"),
+            Either::Second(Ok(())) => {
+                info!("Maze finished, route: {}", route.turns());
+                blink_big_leds(1).await;
+                Mode::set(Mode::Manual);
+            }
+            Either::Second(Err(error)) => {
+                warn!("Maze run failed: {}", error);
+                blink_big_leds(3).await;
+                Mode::set(Mode::Manual);
+            }
+        }
+    }
+}
+
+async fn solve_maze(mut run: MazeRun<'_>) -> Result<(), MazeError> {
+    let base_speed = match run {
+        MazeRun::Explore(_) => MAZE_EXPLORE_SPEED,
+        MazeRun::SpeedRun(_) => MAZE_SPEED_RUN_SPEED,
+    };
+    let mut follower = LineFollower::new(LineFollowConfig {
+        base_speed,
+        ..LineFollowConfig::DEFAULT
+    });
+    let mut detector = JunctionDetector::default();
+    let mut next_turn = 0;
+    let mut ticker = Ticker::every(Duration::from_millis(LINE_FOLLOW_INTERVAL_MS));
+
+    loop {
+        ticker.next().await;
+        let Some(line) = LINE_STATE.try_get() else {
+            continue;
+        };
+
+        let event = detector.update(&line);
+        // From the first sample of a branch to the end of the crossing, the sensors may
+        // all be over the junction or the finish pad
+        safety::expect_dark_floor(detector.over_branch() || event.is_some());
+        let junction = match event {
+            // Losing the line is a dead end here, so the follower never gets to search
+            None => {
+                if let FollowStep::Drive(motion) = follower.step(&line, Instant::now()) {
+                    MOTORS_CHANNEL.send(MotorCommand::Drive(motion)).await;
+                }
+                continue;
+            }
+            Some(MazeEvent::DeadEnd) => Junction::default(),
+            Some(MazeEvent::Branch { left, right }) => match cross_junction(left, right).await {
+                Some(junction) => junction,
+                None => return Ok(()),
+            },
+        };
+
+        let turn = match &mut run {
+            MazeRun::Explore(route) => {
+                let turn = junction.left_hand_turn();
+                if !route.push(turn) {
+                    return Err(MazeError::RouteFull);
+                }
+                turn
+            }
+            MazeRun::SpeedRun(turns) => {
+                if junction.is_dead_end() {
+                    return Err(MazeError::OffRoute);
+                }
+                let turn = *turns.get(next_turn).ok_or(MazeError::OffRoute)?;
+                next_turn += 1;
+                turn
+            }
+        };
+        debug!("Junction, turning {}", turn);
+
+        take_turn(turn).await?;
+        detector.reset();
+        follower.reset();
+    }
+}
+
+// Drive over a branch to see if the line also goes on straight. None at the finish pad
+async fn cross_junction(mut left: bool, mut right: bool) -> Option<Junction> {
+    let inch = Manoeuvre::forward(MAZE_EXPLORE_SPEED, Duration::from_millis(MAZE_INCH_MS));
+    let sample = async {
+        loop {
+            if let Some(line) = LINE_STATE.try_get() {
+                let (left_now, right_now) = JunctionDetector::sides(&line);
+                left |= left_now;
+                right |= right_now;
+            }
+            Timer::after_millis(LINE_SAMPLE_INTERVAL_MS).await;
+        }
+    };
+    // Ends braked over the junction
+    select(inch.run(&MAZE_MANOEUVRES), sample).await;
+
+    let line = LINE_STATE.try_get()?;
+    if JunctionDetector::at_goal(&line) {
+        return None;
+    }
+    Some(Junction {
+        left,
+        straight: JunctionDetector::straight(&line),
+        right,
+    })
+}
+
+// Spin until the center sensor leaves the current line and finds the next one
+async fn take_turn(turn: Turn) -> Result<(), MazeError> {
+    let turn = match turn {
+        Turn::Straight => return Ok(()),
+        // Turning back counterclockwise keeps the left hand on the wall
+        Turn::Left | Turn::Back => -MAZE_TURN_SPEED,
+        Turn::Right => MAZE_TURN_SPEED,
+    };
+    let motion = Motion {
+        forward: 0,
+        strafe: 0,
+        turn,
+    };
+    MOTORS_CHANNEL.send(MotorCommand::Drive(motion)).await;
+
+    let deadline = Instant::now() + Duration::from_millis(MAZE_TURN_TIMEOUT_MS);
+    let mut left_line = false;
+    loop {
+        Timer::after_millis(LINE_SAMPLE_INTERVAL_MS).await;
+        if Instant::now() > deadline {
+            return Err(MazeError::TurnTimeout);
+        }
+        let on_line = LINE_STATE
+            .try_get()
+            .is_some_and(|line| JunctionDetector::straight(&line));
+        if !on_line {
+            left_line = true;
+        } else if left_line {
+            return Ok(());
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn wheel_trim() {
+    let mut mode = MODE.receiver().unwrap();
+    debug!("Wheel trim initialized");
 
-        Timer::after(Duration::from_millis(120)).await;
+    loop {
+        mode.get_and(|mode| *mode == Mode::WheelTrim).await;
+        info!("Wheel trim started, drive with Up and steer the drift away");
+        // Button presses from before the mode was selected aren't meant for the trim
+        WHEEL_TRIM_CHANNEL.clear();
+
+        let result = select(mode.changed(), trim_wheels()).await;
+        stop_unless_taken_over(&result).await;
+
+        match result {
+            Either::First(_) => {
+                // Leaving without saving goes back to what was there before
+                let saved = storage::load().unwrap_or(WheelCalibration::DEFAULT);
+                WHEEL_CALIBRATION.sender().send(saved);
+                info!("Wheel trim cancelled");
+            }
+            Either::Second(calibration) => {
+                if let Err(error) = storage::save(&calibration) {
+                    warn!("Wheel calibration not saved: {}", error);
+                }
+                info!("Wheel trim done: {}", calibration);
+                blink_big_leds(1).await;
+                Mode::set(Mode::Manual);
+            }
+        }
+    }
+}
+
+// Every edit takes effect right away, so the result shows on the next test drive
+async fn trim_wheels() -> WheelCalibration {
+    const WHEEL_NUMBERS: [&str; 4] = ["1", "2", "3", "4"];
+    let mut calibration = WHEEL_CALIBRATION
+        .try_get()
+        .unwrap_or(WheelCalibration::DEFAULT);
+    let mut selected = 0;
+    loop {
+        match WHEEL_TRIM_CHANNEL.receive().await {
+            WheelTrimCommand::Steer(side) => calibration.steer(side),
+            WheelTrimCommand::ToggleInverted(wheel) => calibration.toggle_inverted(wheel),
+            WheelTrimCommand::SelectNextWheel => {
+                selected = (selected + 1) % Wheel::ALL.len();
+                let _ = DISPLAY_CHANNEL.try_send(DisplayCommand::Text(WHEEL_NUMBERS[selected]));
+                debug!("Wheel trim: deadband of {}", Wheel::ALL[selected]);
+                continue;
+            }
+            WheelTrimCommand::NextDeadband => calibration.next_deadband(Wheel::ALL[selected]),
+            // Straight ahead without the heading hold, the drift is what is being trimmed
+            WheelTrimCommand::TestDrive => {
+                let motion = Motion {
+                    forward: 0xFF,
+                    strafe: 0,
+                    turn: 0,
+                };
+                MOTORS_CHANNEL.send(MotorCommand::Drive(motion)).await;
+                continue;
+            }
+            WheelTrimCommand::TestStop => {
+                MOTORS_CHANNEL
+                    .send(MotorCommand::Stop(StopMode::BrakeThenCoast))
+                    .await;
+                continue;
+            }
+            WheelTrimCommand::Save => return calibration,
+        }
+        WHEEL_CALIBRATION.sender().send(calibration);
+        debug!("Wheel trim: {}", calibration);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn recorder() {
+    let mut mode = MODE.receiver().unwrap();
+    let mut motion_state = MOTION_STATE.receiver().unwrap();
+    debug!("Recorder initialized");
+
+    loop {
+        match mode
+            .get_and(|mode| matches!(mode, Mode::Record | Mode::Replay(_)))
+            .await
+        {
+            Mode::Replay(key) => {
+                let Some(recording) = storage::load_from::<Recording>(Slot::Recording(key)) else {
+                    warn!("Nothing recorded under key {}", key);
+                    Mode::set(Mode::Manual);
+                    continue;
+                };
+                info!("Replaying {} steps", recording.steps().len());
+
+                let result = select(mode.changed(), replay(&recording)).await;
+                stop_unless_taken_over(&result).await;
+                if let Either::Second(()) = result {
+                    info!("Replay done");
+                    Mode::set(Mode::Manual);
+                }
+            }
+            _ => {
+                info!("Recording, drive and press a recording key to keep it");
+                // Button presses from before the mode was selected aren't part of it
+                RECORDER_CHANNEL.clear();
+
+                match select(mode.changed(), record()).await {
+                    Either::First(_) => info!("Recording discarded"),
+                    Either::Second((key, recording)) => {
+                        MOTORS_CHANNEL
+                            .send(MotorCommand::Stop(StopMode::BrakeThenCoast))
+                            .await;
+                        // Writing flash stalls the executor, so the wheels have to be still first
+                        motion_state.get_and(|state| !state.is_moving()).await;
+                        match storage::save_to(Slot::Recording(key), &recording) {
+                            Ok(()) => {
+                                info!("Recorded {} steps", recording.steps().len());
+                                DISPLAY_CHANNEL.send(DisplayCommand::Icon(Icon::Tick)).await;
+                                blink_big_leds(1).await;
+                            }
+                            Err(error) => warn!("Recording not saved: {}", error),
+                        }
+                        Mode::set(Mode::Manual);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Collects what the remote sends until a recording key is pressed
+async fn record() -> (u8, Recording) {
+    let mut recording = Recording::new();
+    let mut last: Option<Instant> = None;
+    loop {
+        match RECORDER_CHANNEL.receive().await {
+            RecorderEvent::Step(at, action) => {
+                let delay = last.map_or(0, |last| (at - last).as_millis());
+                last = Some(at);
+                if !recording.push(delay, action) {
+                    warn!("Recording full, step dropped");
+                }
+            }
+            RecorderEvent::Save(key) => return (key, recording),
+        }
+    }
+}
+
+async fn replay(recording: &Recording) {
+    for step in recording.steps() {
+        Timer::after_millis(step.delay_ms as u64).await;
+        match step.action {
+            Action::Motor(command) => MOTORS_CHANNEL.send(command).await,
+            Action::BigLeds(command) => BIG_LEDS_CHANNEL.send(command).await,
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn compass() {
+    let mut magnetic_field = MAGNETIC_FIELD.receiver().unwrap();
+    let heading = HEADING.sender();
+    debug!("Compass initialized");
+
+    loop {
+        let field = CompassCalibration::current().apply(&magnetic_field.changed().await);
+        // The accelerometer is faster, its latest sample is recent enough
+        let Some(gravity) = ACCELERATION.try_get() else {
+            continue;
+        };
+        if let Some(value) = compass::heading(&field, &gravity) {
+            heading.send(value);
+            odometry::correct_heading(value);
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn compass_calibration() {
+    let mut mode = MODE.receiver().unwrap();
+    debug!("Compass calibration initialized");
+
+    loop {
+        mode.get_and(|mode| *mode == Mode::CompassCalibration).await;
+        info!("Compass calibration started, keep magnets and metal away from the car");
+
+        let result = select(mode.changed(), calibrate_compass()).await;
+        stop_unless_taken_over(&result).await;
+
+        match result {
+            Either::First(_) => info!("Compass calibration cancelled"),
+            Either::Second(Some(calibration)) => {
+                COMPASS_CALIBRATION.sender().send(calibration);
+                if let Err(error) = storage::save(&calibration) {
+                    warn!("Compass calibration not saved: {}", error);
+                }
+                info!("Compass calibration done: {}", calibration);
+                DISPLAY_CHANNEL.send(DisplayCommand::Icon(Icon::Tick)).await;
+                blink_big_leds(1).await;
+                Mode::set(Mode::Manual);
+            }
+            Either::Second(None) => {
+                warn!("Compass calibration failed, the field hardly changed");
+                DISPLAY_CHANNEL
+                    .send(DisplayCommand::Icon(Icon::Cross))
+                    .await;
+                blink_big_leds(3).await;
+                Mode::set(Mode::Manual);
+            }
+        }
+        BIG_LEDS_CHANNEL.send(BigLedCommand::Brightness(0x00)).await;
+    }
+}
+
+// Spin slowly on the spot a few times, sampling the raw field the whole time
+async fn calibrate_compass() -> Option<CompassCalibration> {
+    let mut magnetic_field = MAGNETIC_FIELD.receiver().unwrap();
+    let mut calibrator = CompassCalibrator::new(CompassCalibration::current());
+    let duration = odometry::time_to_turn(
+        COMPASS_CALIBRATION_ROTATIONS * 2.0 * PI,
+        COMPASS_CALIBRATION_TURN,
+    );
+    let start = Instant::now();
+    let mut shown = None;
+
+    let sample = async {
+        loop {
+            calibrator.sample(&magnetic_field.changed().await);
+            let progress = (Instant::now() - start).as_millis() * 0xFF / duration.as_millis();
+            show_progress(progress.min(0xFF) as u8, &mut shown);
+        }
+    };
+    // After backing off a stall the spin goes on for the time it had left
+    let spin = async {
+        loop {
+            let left = duration
+                .checked_sub(Instant::now() - start)
+                .unwrap_or(Duration::MIN);
+            let end = Manoeuvre::rotate(COMPASS_CALIBRATION_TURN, left)
+                .run(&COMPASS_CALIBRATION_MANOEUVRES)
+                .await;
+            if end != ManoeuvreEnd::Stalled {
+                return end;
+            }
+        }
+    };
+    if let Either::First(end) = select(spin, sample).await
+        && end != ManoeuvreEnd::Completed
+    {
+        warn!("Compass calibration spin stopped: {}", end);
+        return None;
+    }
+    calibrator.finish()
+}
+
+#[embassy_executor::task]
+pub async fn crash_guard() {
+    let mut acceleration = ACCELERATION.receiver().unwrap();
+    let mut hold = SAFETY_HOLD.receiver().unwrap();
+    let mut detector = CrashDetector::default();
+    debug!("Crash guard initialized");
+
+    loop {
+        let sample = acceleration.changed().await;
+        let Some(event) = detector.update(sample, Instant::now()) else {
+            continue;
+        };
+        warn!("Crash: {}", event);
+        // The hold goes first, the motors task looks at it once stopped
+        event.hold();
+        if Mode::current() != Mode::Manual {
+            Mode::set(Mode::Manual);
+        }
+        SAFETY_STOP.signal(event);
+        DISPLAY_CHANNEL
+            .send(DisplayCommand::Icon(Icon::Cross))
+            .await;
+
+        select(hold.get_and(|held| held.is_none()), flash_hazard()).await;
+        BIG_LEDS_CHANNEL.send(BigLedCommand::Brightness(0x00)).await;
+        DISPLAY_CHANNEL.send(DisplayCommand::Clear).await;
+        detector.reset();
+    }
+}
+
+// Both headlights blinking until cancelled
+async fn flash_hazard() {
+    loop {
+        blink_big_leds(1).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn stall_guard() {
+    let mut acceleration = ACCELERATION.receiver().unwrap();
+    let mut detector = StallDetector::default();
+    let mut retries = 0;
+    let mut last_stall: Option<Instant> = None;
+    debug!("Stall guard initialized");
+
+    loop {
+        let sample = acceleration.changed().await;
+        let now = Instant::now();
+        let driven = MOTION_STATE
+            .try_get()
+            .is_some_and(|state| state.is_moving());
+        if !detector.update(sample, driven, now) || SafetyEvent::held().is_some() {
+            continue;
+        }
+
+        if last_stall.is_some_and(|last| now - last > STALL_RETRY_WINDOW) {
+            retries = 0;
+        }
+        last_stall = Some(now);
+        let back_off = !Mode::current().is_manual() && retries < STALL_MAX_RETRIES;
+        if back_off {
+            retries += 1;
+            warn!("Stalled, backing off to try again ({})", retries);
+            SAFETY_STOP.signal(SafetyEvent::Stall { back_off });
+        } else {
+            warn!("Stalled, stopping");
+            if Mode::current() != Mode::Manual {
+                Mode::set(Mode::Manual);
+            }
+            SAFETY_STOP.signal(SafetyEvent::Stall { back_off });
+            blink_big_leds(3).await;
+            DISPLAY_CHANNEL.send(DisplayCommand::Text("STALL")).await;
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn return_to_start() {
+    let mut mode = MODE.receiver().unwrap();
+    let mut motion_state = MOTION_STATE.receiver().unwrap();
+    debug!("Return to start initialized");
+
+    loop {
+        mode.get_and(|mode| *mode == Mode::ReturnToStart).await;
+        // The pose only holds still once the wheels do
+        MOTORS_CHANNEL
+            .send(MotorCommand::Stop(StopMode::Brake))
+            .await;
+        motion_state.get_and(|state| !state.is_moving()).await;
+        let pose = odometry::pose();
+        info!("Returning to start from {}", pose);
+
+        let result = select(mode.changed(), drive_to_start(pose)).await;
+        stop_unless_taken_over(&result).await;
+        match result {
+            Either::First(_) => info!("Return to start abandoned"),
+            Either::Second(ManoeuvreEnd::Completed) => {
+                // Whatever drift there was, this is the start again
+                info!("Back at the start, off by about {}", odometry::pose());
+                odometry::reset();
+                Mode::set(Mode::Manual);
+            }
+            Either::Second(end) => {
+                warn!("Return to start stopped: {}", end);
+                Mode::set(Mode::Manual);
+            }
+        }
+    }
+}
+
+// After backing off a stall the way back starts over from where the car ended up
+async fn drive_to_start(mut pose: Pose) -> ManoeuvreEnd {
+    loop {
+        let end = head_for_start(pose).await;
+        if end != ManoeuvreEnd::Stalled {
+            return end;
+        }
+        pose = odometry::pose();
+        info!("Returning to start again from {}", pose);
+    }
+}
+
+// Face the start, drive straight to it and turn to the starting direction
+async fn head_for_start(pose: Pose) -> ManoeuvreEnd {
+    let distance = hypot(pose.x, pose.y);
+    if distance > RETURN_CLOSE_ENOUGH_MM {
+        let bearing = atan2(-pose.y, -pose.x);
+        let end = rotate_by(wrap_angle(bearing - pose.heading)).await;
+        if end != ManoeuvreEnd::Completed {
+            return end;
+        }
+        let duration = odometry::time_to_drive(distance, RETURN_SPEED);
+        let end = Manoeuvre::forward(RETURN_SPEED, duration)
+            .run(&RETURN_MANOEUVRES)
+            .await;
+        if end != ManoeuvreEnd::Completed {
+            return end;
+        }
+    }
+    match odometry::start_heading() {
+        Some(heading) => {
+            Rotation::to(heading, RETURN_TURN_TIMEOUT)
+                .run(&RETURN_MANOEUVRES)
+                .await
+        }
+        None => rotate_by(wrap_angle(-odometry::pose().heading)).await,
+    }
+}
+
+// Clockwise for positive angles, on the compass when there is one
+async fn rotate_by(angle: f32) -> ManoeuvreEnd {
+    if HEADING.try_get().is_some() {
+        return Rotation::by(angle, RETURN_TURN_TIMEOUT)
+            .run(&RETURN_MANOEUVRES)
+            .await;
+    }
+    let rate = if angle < 0.0 {
+        -RETURN_TURN_SPEED
+    } else {
+        RETURN_TURN_SPEED
+    };
+    let duration = odometry::time_to_turn(angle, RETURN_TURN_SPEED);
+    Manoeuvre::rotate(rate, duration)
+        .run(&RETURN_MANOEUVRES)
+        .await
+}
+
+// Watches for the table edge whatever the car is doing
+#[embassy_executor::task]
+pub async fn cliff_guard() {
+    let mut line_state = LINE_STATE.receiver().unwrap();
+    let mut detector = CliffDetector::default();
+    debug!("Cliff guard initialized");
+
+    loop {
+        let line = line_state.changed().await;
+        // A car standing still can't drive off anything, and the pads and junctions of
+        // a line track are as dark as the edge
+        let driven = MOTION_STATE
+            .try_get()
+            .is_some_and(|state| state.is_moving());
+        if !driven || safety::dark_floor_expected() {
+            detector = CliffDetector::default();
+            continue;
+        }
+        let calibration = LINE_CALIBRATION
+            .try_get()
+            .unwrap_or(LineCalibration::DEFAULT);
+        // A lifted or tipped over car sees no floor either, it already waits for Ok
+        if detector.update(&line, &calibration, Instant::now()) && SafetyEvent::held().is_none() {
+            warn!("No floor under the car");
+            // Autonomous modes would only drive back to the edge
+            if Mode::current() != Mode::Manual {
+                Mode::set(Mode::Manual);
+            }
+            SAFETY_STOP.signal(SafetyEvent::Cliff);
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn motion_sensor(
+    p_twim: Peri<'static, TWISPI1>,
+    p_i2c_int_sda: Peri<'static, P0_16>,
+    p_i2c_int_scl: Peri<'static, P0_08>,
+    p_i2c_int_irq: Peri<'static, P0_25>,
+) {
+    static RAM_BUFFER: ConstStaticCell<[u8; 16]> = ConstStaticCell::new([0; 16]);
+    let mut sensor = Lsm303agr::new(Twim::new(
+        p_twim,
+        MotionSensorIrqs,
+        p_i2c_int_sda,
+        p_i2c_int_scl,
+        embassy_nrf::twim::Config::default(),
+        RAM_BUFFER.take(),
+    ));
+    let mut irq = Input::new(p_i2c_int_irq, Pull::Up);
+    let acceleration = ACCELERATION.sender();
+    let magnetic_field = MAGNETIC_FIELD.sender();
+
+    loop {
+        if let Err(error) = sensor.init().await {
+            warn!("Motion sensor init failed: {}", error);
+            Timer::after_millis(MOTION_SENSOR_RETRY_MS).await;
+            continue;
+        }
+        debug!("Motion sensor initialized");
+
+        // The magnetometer is slower, it gets looked at whenever the accelerometer is ready
+        let error = loop {
+            let _ = with_timeout(
+                Duration::from_millis(MOTION_SENSOR_POLL_MS),
+                irq.wait_for_low(),
+            )
+            .await;
+            match sensor.acceleration().await {
+                Ok(Some(sample)) => acceleration.send(sample),
+                // Somebody else on the line, or nothing came in time
+                Ok(None) => Timer::after_millis(MOTION_SENSOR_SAMPLE_MS).await,
+                Err(error) => break error,
+            }
+            match sensor.magnetic_field().await {
+                Ok(Some(sample)) => magnetic_field.send(sample),
+                Ok(None) => {}
+                Err(error) => break error,
+            }
+        };
+        warn!("Motion sensor read failed: {}", error);
+    }
+}
+
+// LED matrix of the micro:bit, showing whatever came last through DISPLAY_CHANNEL
+#[cfg(feature = "full-matrix")]
+#[embassy_executor::task]
+pub async fn display(rows: [Peri<'static, AnyPin>; 5], columns: [Peri<'static, AnyPin>; 5]) {
+    let mut display = Display::new(rows, columns);
+    let mut content = Content::new(DisplayCommand::Clear, Instant::now());
+    debug!("Display initialized");
+
+    loop {
+        // Commands only take over between two frames, so no row is ever left lit
+        while let Ok(command) = DISPLAY_CHANNEL.try_receive() {
+            content = Content::new(command, Instant::now());
+        }
+        display.refresh(&content.frame(Instant::now())).await;
+    }
+}
+
+// Without the whole matrix, what would be on the display only goes to the log
+#[cfg(not(feature = "full-matrix"))]
+#[embassy_executor::task]
+pub async fn display() {
+    debug!("Display initialized, log only");
+    loop {
+        debug!("Display: {}", DISPLAY_CHANNEL.receive().await);
+    }
+}
+
+// The selected mode when it changes, and where the remote drives the car
+#[embassy_executor::task]
+pub async fn dashboard() {
+    let mut mode = MODE.receiver().unwrap();
+    let mut motion_state = MOTION_STATE.receiver().unwrap();
+    let mut direction = Direction::Stopped;
+    debug!("Dashboard initialized");
+
+    loop {
+        let command = match select(mode.changed(), motion_state.changed()).await {
+            Either::First(mode) => DisplayCommand::Text(mode.label()),
+            Either::Second(state) => {
+                let changed = state.direction() != direction;
+                direction = state.direction();
+                // Autonomous modes keep their number up
+                if !changed || !Mode::current().is_manual() {
+                    continue;
+                }
+                DisplayCommand::Icon(Icon::direction(direction))
+            }
+        };
+        // The crash guard keeps its cross up until the car is resumed
+        if SafetyEvent::held().is_none() {
+            DISPLAY_CHANNEL.send(command).await;
+        }
     }
 }
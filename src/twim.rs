@@ -1,27 +1,689 @@
-use embassy_nrf::{bind_interrupts, peripherals::TWISPI0, twim};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use crate::expander::{Expander, Headlight, Register, WHEEL_STOPPED, Wheel, WheelInputs};
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    sync::atomic::{AtomicU32, Ordering},
+    task::Poll,
+};
+use embassy_nrf::{
+    Peri, bind_interrupts,
+    gpio::{Flex, OutputDrive, Pull},
+    peripherals::{P0_26, P1_00, TWISPI0},
+    twim::{self, Twim},
+};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::ThreadModeRawMutex},
+    signal::Signal,
+    waitqueue::{MultiWakerRegistration, WakerRegistration},
+};
+use embassy_time::{Duration, Timer, with_timeout};
+use heapless::{Deque, Vec};
 
 bind_interrupts!(pub struct Irqs {
     TWISPI0 => twim::InterruptHandler<TWISPI0>;
 });
 
-pub static TWIN_CHANNEL: Channel<ThreadModeRawMutex, TwinCommand, 1> = Channel::new();
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(10);
+const MAX_ATTEMPTS: u32 = 3;
+// Doubled after every failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(1);
+// Commands failing in a row before the bus is considered stuck
+pub const RECOVERY_THRESHOLD: u32 = 3;
+// Commands waiting in each lane before the senders have to wait
+const TWIN_LANE_DEPTH: usize = 4;
+const TWIN_LANE_COUNT: usize = 3;
+// Tasks that can be waiting on a full lane at the same time
+const TWIN_MAX_SENDERS: usize = 4;
+
+// The expander behind the TWIM of the edge connector
+pub type TwinExpander<'d> = Expander<Twim<'d, TWISPI0>>;
+
+pub static TWIN_LANES: TwinLanes = TwinLanes::new();
+pub static TWIN_STATS: TwinStats = TwinStats::new();
+
+// Where the bus task reports how a command went, along with the sequence number
+// of the command so a client can tell its own reply from a stale one
+pub type TwinReply = Signal<ThreadModeRawMutex, (u32, Result<TwinResponse, TwinError>)>;
+
+#[derive(Clone, Copy)]
+pub enum TwinRequest {
+    // Both inputs of every motor driver, same order as Wheel::ALL
+    Wheels([WheelInputs; 4]),
+    Headlight { headlight: Headlight, value: u8 },
+    ReadHeadlight(Headlight),
+}
+
+impl TwinRequest {
+    // Registers set by a write and their new values, none for a read
+    fn writes(&self) -> Vec<(Register, u8), { Register::COUNT }> {
+        let mut writes = Vec::new();
+        match *self {
+            TwinRequest::Wheels(inputs) => {
+                writes.extend(Wheel::ALL.into_iter().zip(inputs).flat_map(
+                    |(wheel, (input_a, input_b))| {
+                        let (register_a, register_b) = wheel.registers();
+                        [(register_a, input_a), (register_b, input_b)]
+                    },
+                ));
+            }
+            TwinRequest::Headlight { headlight, value } => {
+                writes.extend([(headlight.register(), value)]);
+            }
+            TwinRequest::ReadHeadlight(_) => {}
+        }
+        writes
+    }
+
+    // Both set the same registers, so the older value would be overwritten right away
+    fn same_target(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TwinRequest::Wheels(_), TwinRequest::Wheels(_)) => true,
+            (
+                TwinRequest::Headlight { headlight, .. },
+                TwinRequest::Headlight {
+                    headlight: other_headlight,
+                    ..
+                },
+            ) => headlight == other_headlight,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum TwinResponse {
+    Written,
+    Read(u8),
+}
 
 pub struct TwinCommand {
-    channel: u8,
-    value: u8,
+    request: TwinRequest,
+    reply: Option<(&'static TwinReply, u32)>,
 }
 
 impl TwinCommand {
-    pub fn new(channel: u8, value: u8) -> Self {
-        Self { channel, value }
+    pub fn new(request: TwinRequest) -> Self {
+        Self {
+            request,
+            reply: None,
+        }
+    }
+
+    pub fn with_reply(mut self, reply: &'static TwinReply, sequence: u32) -> Self {
+        self.reply = Some((reply, sequence));
+        self
+    }
+
+    pub fn request(&self) -> TwinRequest {
+        self.request
+    }
+
+    pub fn reply(&self, result: Result<TwinResponse, TwinError>) {
+        if let Some((reply, sequence)) = self.reply {
+            reply.signal((sequence, result));
+        }
+    }
+
+    // The newer command will write over this one, so it counts as written
+    fn supersede(&self, newer: &Self) {
+        let same_client = match (self.reply, newer.reply) {
+            (Some((reply, _)), Some((newer_reply, _))) => core::ptr::eq(reply, newer_reply),
+            _ => false,
+        };
+        // A client has a single reply, the newer command is the one it is waiting for
+        if !same_client {
+            self.reply(Ok(TwinResponse::Written));
+        }
+    }
+}
+
+// Served in this order, a lane only gets the bus when all the ones above are empty
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TwinPriority {
+    Emergency,
+    Motion,
+    Lighting,
+}
+
+impl TwinPriority {
+    const fn lane(self) -> usize {
+        match self {
+            TwinPriority::Emergency => 0,
+            TwinPriority::Motion => 1,
+            TwinPriority::Lighting => 2,
+        }
+    }
+}
+
+struct LaneState {
+    lanes: [Deque<TwinCommand, TWIN_LANE_DEPTH>; TWIN_LANE_COUNT],
+    bus_task: WakerRegistration,
+    senders: MultiWakerRegistration<TWIN_MAX_SENDERS>,
+}
+
+impl LaneState {
+    const fn new() -> Self {
+        Self {
+            lanes: [Deque::new(), Deque::new(), Deque::new()],
+            bus_task: WakerRegistration::new(),
+            senders: MultiWakerRegistration::new(),
+        }
+    }
+
+    // Hands the command back when its lane is full
+    fn push(&mut self, priority: TwinPriority, new: TwinCommand) -> Result<(), TwinCommand> {
+        // Older writes to the same registers are stale, only the newest value is sent.
+        // Higher lanes are left alone, they go first anyway and keep the order
+        for lane in &mut self.lanes[priority.lane()..] {
+            for _ in 0..lane.len() {
+                let Some(queued) = lane.pop_front() else {
+                    break;
+                };
+                if queued.request.same_target(&new.request) {
+                    queued.supersede(&new);
+                } else {
+                    // Can't fail, the slot was just freed
+                    let _ = lane.push_back(queued);
+                }
+            }
+        }
+        self.lanes[priority.lane()].push_back(new)
+    }
+
+    fn pop(&mut self) -> Option<TwinCommand> {
+        self.lanes.iter_mut().find_map(|lane| lane.pop_front())
+    }
+}
+
+// Queues between the tasks and the bus task, one per priority
+pub struct TwinLanes {
+    state: Mutex<ThreadModeRawMutex, RefCell<LaneState>>,
+}
+
+impl TwinLanes {
+    const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(LaneState::new())),
+        }
+    }
+
+    pub async fn send(&self, priority: TwinPriority, command: TwinCommand) {
+        let mut command = Some(command);
+        poll_fn(|cx| {
+            self.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                let Some(new) = command.take() else {
+                    return Poll::Ready(());
+                };
+                match state.push(priority, new) {
+                    Ok(()) => {
+                        state.bus_task.wake();
+                        Poll::Ready(())
+                    }
+                    Err(new) => {
+                        command = Some(new);
+                        state.senders.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+
+    pub async fn receive(&self) -> TwinCommand {
+        poll_fn(|cx| {
+            self.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                match state.pop() {
+                    Some(command) => {
+                        state.senders.wake();
+                        Poll::Ready(command)
+                    }
+                    None => {
+                        state.bus_task.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await
+    }
+}
+
+// Sends commands and waits for the bus task to answer. Each task talking to the
+// bus needs its own client, a reply meant for one task would wake up the other
+pub struct TwinClient {
+    priority: TwinPriority,
+    reply: TwinReply,
+    // Sequence number of the last request sent
+    sequence: AtomicU32,
+}
+
+impl TwinClient {
+    pub const fn new(priority: TwinPriority) -> Self {
+        Self {
+            priority,
+            reply: Signal::new(),
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    pub async fn set_wheels(&'static self, inputs: [WheelInputs; 4]) -> Result<(), TwinError> {
+        self.request(TwinRequest::Wheels(inputs)).await.map(|_| ())
+    }
+
+    pub async fn set_headlight(
+        &'static self,
+        headlight: Headlight,
+        value: u8,
+    ) -> Result<(), TwinError> {
+        self.request(TwinRequest::Headlight { headlight, value })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn headlight(&'static self, headlight: Headlight) -> Result<u8, TwinError> {
+        match self.request(TwinRequest::ReadHeadlight(headlight)).await? {
+            TwinResponse::Read(value) => Ok(value),
+            TwinResponse::Written => Err(TwinError::Bus),
+        }
+    }
+
+    async fn request(&'static self, request: TwinRequest) -> Result<TwinResponse, TwinError> {
+        // A request cancelled while on the bus still gets its reply later on, the
+        // sequence number keeps it from being taken for this one
+        let sequence = self
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        TWIN_LANES
+            .send(
+                self.priority,
+                TwinCommand::new(request).with_reply(&self.reply, sequence),
+            )
+            .await;
+        loop {
+            let (replied, result) = self.reply.wait().await;
+            if replied == sequence {
+                return result;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TwinError {
+    // Nobody answered at the address
+    AddressNack,
+    // The device refused a byte, usually a bad register
+    DataNack,
+    // The transfer never finished, something holds the bus
+    Timeout,
+    // Anything else the peripheral complained about
+    Bus,
+}
+
+impl From<twim::Error> for TwinError {
+    fn from(error: twim::Error) -> Self {
+        match error {
+            twim::Error::AddressNack => TwinError::AddressNack,
+            twim::Error::DataNack => TwinError::DataNack,
+            twim::Error::Timeout => TwinError::Timeout,
+            _ => TwinError::Bus,
+        }
+    }
+}
+
+pub struct TwinStats {
+    // Writes dropped because the registers already held the values
+    pub skipped_writes: AtomicU32,
+    pub address_nacks: AtomicU32,
+    pub data_nacks: AtomicU32,
+    pub timeouts: AtomicU32,
+    pub bus_errors: AtomicU32,
+    pub retries: AtomicU32,
+    pub recoveries: AtomicU32,
+}
+
+impl TwinStats {
+    const fn new() -> Self {
+        Self {
+            skipped_writes: AtomicU32::new(0),
+            address_nacks: AtomicU32::new(0),
+            data_nacks: AtomicU32::new(0),
+            timeouts: AtomicU32::new(0),
+            bus_errors: AtomicU32::new(0),
+            retries: AtomicU32::new(0),
+            recoveries: AtomicU32::new(0),
+        }
+    }
+
+    fn count(&self, error: TwinError) {
+        let counter = match error {
+            TwinError::AddressNack => &self.address_nacks,
+            TwinError::DataNack => &self.data_nacks,
+            TwinError::Timeout => &self.timeouts,
+            TwinError::Bus => &self.bus_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl defmt::Format for TwinStats {
+    fn format(&self, f: defmt::Formatter) {
+        let load = |counter: &AtomicU32| counter.load(Ordering::Relaxed);
+        defmt::write!(
+            f,
+            "{} writes skipped, {} address NACKs, {} data NACKs, {} timeouts, {} bus errors, {} retries, {} recoveries",
+            load(&self.skipped_writes),
+            load(&self.address_nacks),
+            load(&self.data_nacks),
+            load(&self.timeouts),
+            load(&self.bus_errors),
+            load(&self.retries),
+            load(&self.recoveries),
+        );
+    }
+}
+
+// Copy of what the expander registers hold, kept by the bus task to skip writes
+// that wouldn't change anything
+pub struct RegisterShadow {
+    // None until the register has been written or read
+    values: [Option<u8>; Register::COUNT],
+    // The last write failed, the expander may or may not hold the value
+    stale: [bool; Register::COUNT],
+}
+
+impl RegisterShadow {
+    pub const fn new() -> Self {
+        Self {
+            values: [None; Register::COUNT],
+            stale: [false; Register::COUNT],
+        }
+    }
+
+    fn holds(&self, writes: &[(Register, u8)]) -> bool {
+        writes.iter().all(|(register, value)| {
+            !self.stale[register.index()] && self.values[register.index()] == Some(*value)
+        })
+    }
+
+    fn record(&mut self, writes: &[(Register, u8)], written: bool) {
+        for (register, value) in writes {
+            self.values[register.index()] = Some(*value);
+            self.stale[register.index()] = !written;
+        }
+    }
+
+    // Boot sequence, the wheels may still be turning from before a reset of the micro:bit
+    pub async fn init(&mut self, expander: &mut TwinExpander<'_>) -> Result<(), TwinError> {
+        let result = timed(expander.init()).await;
+        let stopped = TwinRequest::Wheels([WHEEL_STOPPED; 4]).writes();
+        self.record(&stopped, result.is_ok());
+        result
+    }
+
+    pub async fn transfer(
+        &mut self,
+        expander: &mut TwinExpander<'_>,
+        request: TwinRequest,
+    ) -> Result<TwinResponse, TwinError> {
+        let writes = request.writes();
+        if !writes.is_empty() && self.holds(&writes) {
+            TWIN_STATS.skipped_writes.fetch_add(1, Ordering::Relaxed);
+            return Ok(TwinResponse::Written);
+        }
+
+        let result = transfer_with_retries(expander, request).await;
+        match (request, &result) {
+            (TwinRequest::ReadHeadlight(headlight), Ok(TwinResponse::Read(value))) => {
+                self.record(&[(headlight.register(), *value)], true);
+            }
+            _ => self.record(&writes, result.is_ok()),
+        }
+        result
+    }
+
+    // Force every known register to be written again, after the bus got stuck
+    // nobody can tell what the expander really holds
+    pub async fn resync(&mut self, expander: &mut TwinExpander<'_>) -> Result<(), TwinError> {
+        let mut result = Ok(());
+        for (register, value) in self.known() {
+            let written = timed(expander.write(register, value)).await;
+            self.record(&[(register, value)], written.is_ok());
+            result = result.and(written);
+        }
+        result
+    }
+
+    // Registers whose value has been written or read before, stale or not
+    fn known(&self) -> Vec<(Register, u8), { Register::COUNT }> {
+        Register::ALL
+            .into_iter()
+            .filter_map(|register| Some((register, self.values[register.index()]?)))
+            .collect()
+    }
+}
+
+// Run a request on the expander, retrying with a growing pause in between
+async fn transfer_with_retries(
+    expander: &mut TwinExpander<'_>,
+    request: TwinRequest,
+) -> Result<TwinResponse, TwinError> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let error = match transfer(expander, request).await {
+            Ok(response) => return Ok(response),
+            Err(error) => error,
+        };
+        TWIN_STATS.count(error);
+        if attempt == MAX_ATTEMPTS {
+            return Err(error);
+        }
+        TWIN_STATS.retries.fetch_add(1, Ordering::Relaxed);
+        Timer::after(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn transfer(
+    expander: &mut TwinExpander<'_>,
+    request: TwinRequest,
+) -> Result<TwinResponse, TwinError> {
+    match request {
+        TwinRequest::Wheels(inputs) => timed(expander.set_wheels(inputs))
+            .await
+            .map(|_| TwinResponse::Written),
+        TwinRequest::Headlight { headlight, value } => {
+            timed(expander.set_headlight(headlight, value))
+                .await
+                .map(|_| TwinResponse::Written)
+        }
+        TwinRequest::ReadHeadlight(headlight) => timed(expander.headlight(headlight))
+            .await
+            .map(TwinResponse::Read),
+    }
+}
+
+async fn timed<T>(operation: impl Future<Output = Result<T, twim::Error>>) -> Result<T, TwinError> {
+    match with_timeout(TRANSFER_TIMEOUT, operation).await {
+        Ok(result) => result.map_err(TwinError::from),
+        Err(_) => Err(TwinError::Timeout),
     }
+}
+
+// Clock out whatever device is holding SDA low, then leave the bus with a STOP
+pub async fn recover_bus(p_sda: Peri<'_, P1_00>, p_scl: Peri<'_, P0_26>) {
+    TWIN_STATS.recoveries.fetch_add(1, Ordering::Relaxed);
+    let mut sda = Flex::new(p_sda);
+    let mut scl = Flex::new(p_scl);
+    sda.set_high();
+    scl.set_high();
+    sda.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+    scl.set_as_input_output(Pull::Up, OutputDrive::Standard0Disconnect1);
+
+    // A byte and the ACK is the most a device can still want to send
+    for _ in 0..9 {
+        if sda.is_high() {
+            break;
+        }
+        scl.set_low();
+        Timer::after_micros(10).await;
+        scl.set_high();
+        Timer::after_micros(10).await;
+    }
+
+    sda.set_low();
+    Timer::after_micros(10).await;
+    sda.set_high();
+    Timer::after_micros(10).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRIVING: [WheelInputs; 4] = [(0x80, 0x00); 4];
+
+    fn wheels(inputs: [WheelInputs; 4]) -> TwinCommand {
+        TwinCommand::new(TwinRequest::Wheels(inputs))
+    }
+
+    fn headlight(headlight: Headlight, value: u8) -> TwinCommand {
+        TwinCommand::new(TwinRequest::Headlight { headlight, value })
+    }
+
+    fn wheel_inputs(command: Option<TwinCommand>) -> [WheelInputs; 4] {
+        match command.map(|command| command.request()) {
+            Some(TwinRequest::Wheels(inputs)) => inputs,
+            _ => panic!("not a wheels command"),
+        }
+    }
+
+    fn headlight_value(command: Option<TwinCommand>) -> (Headlight, u8) {
+        match command.map(|command| command.request()) {
+            Some(TwinRequest::Headlight { headlight, value }) => (headlight, value),
+            _ => panic!("not a headlight command"),
+        }
+    }
+
+    #[test]
+    fn shadow_skips_writes_the_registers_already_hold() {
+        let mut shadow = RegisterShadow::new();
+        let stopped = TwinRequest::Wheels([WHEEL_STOPPED; 4]).writes();
+        assert!(!shadow.holds(&stopped));
+
+        shadow.record(&stopped, true);
+        assert!(shadow.holds(&stopped));
+        assert!(!shadow.holds(&TwinRequest::Wheels(DRIVING).writes()));
+        // Nothing is known about the headlights yet
+        assert!(!shadow.holds(&[(Register::LeftHeadlight, 0x00)]));
+    }
+
+    #[test]
+    fn shadow_writes_again_after_a_failed_write() {
+        let mut shadow = RegisterShadow::new();
+        let driving = TwinRequest::Wheels(DRIVING).writes();
+        shadow.record(&driving, false);
+        assert!(!shadow.holds(&driving));
+
+        shadow.record(&driving, true);
+        assert!(shadow.holds(&driving));
+    }
+
+    #[test]
+    fn shadow_resyncs_every_known_register() {
+        let mut shadow = RegisterShadow::new();
+        shadow.record(&TwinRequest::Wheels(DRIVING).writes(), true);
+        shadow.record(&[(Register::RightHeadlight, 0x40)], false);
+
+        let known = shadow.known();
+        assert_eq!(known.len(), 9);
+        assert!(known[..8] == TwinRequest::Wheels(DRIVING).writes());
+        assert!(known[8] == (Register::RightHeadlight, 0x40));
+    }
+
+    #[test]
+    fn newer_writes_supersede_older_ones_in_the_same_lane() {
+        let mut lanes = LaneState::new();
+        assert!(lanes.push(TwinPriority::Motion, wheels(DRIVING)).is_ok());
+        assert!(
+            lanes
+                .push(TwinPriority::Motion, headlight(Headlight::Left, 0x10))
+                .is_ok()
+        );
+        assert!(
+            lanes
+                .push(TwinPriority::Motion, headlight(Headlight::Right, 0x20))
+                .is_ok()
+        );
+        assert!(
+            lanes
+                .push(TwinPriority::Motion, wheels([WHEEL_STOPPED; 4]))
+                .is_ok()
+        );
+        assert!(
+            lanes
+                .push(TwinPriority::Motion, headlight(Headlight::Left, 0x30))
+                .is_ok()
+        );
+
+        // Each target keeps its newest value, in the order the newest ones were sent
+        assert!(headlight_value(lanes.pop()) == (Headlight::Right, 0x20));
+        assert_eq!(wheel_inputs(lanes.pop()), [WHEEL_STOPPED; 4]);
+        assert!(headlight_value(lanes.pop()) == (Headlight::Left, 0x30));
+        assert!(lanes.pop().is_none());
+    }
+
+    #[test]
+    fn emergency_goes_before_motion() {
+        let mut lanes = LaneState::new();
+        assert!(
+            lanes
+                .push(TwinPriority::Lighting, headlight(Headlight::Left, 0x10))
+                .is_ok()
+        );
+        assert!(lanes.push(TwinPriority::Motion, wheels(DRIVING)).is_ok());
+        assert!(
+            lanes
+                .push(TwinPriority::Emergency, wheels([WHEEL_STOPPED; 4]))
+                .is_ok()
+        );
+
+        // The stop also took the queued drive out, it would have undone it
+        assert_eq!(wheel_inputs(lanes.pop()), [WHEEL_STOPPED; 4]);
+        assert!(headlight_value(lanes.pop()) == (Headlight::Left, 0x10));
+        assert!(lanes.pop().is_none());
+    }
+
+    #[test]
+    fn lower_lanes_leave_higher_ones_alone() {
+        let mut lanes = LaneState::new();
+        assert!(
+            lanes
+                .push(TwinPriority::Emergency, wheels([WHEEL_STOPPED; 4]))
+                .is_ok()
+        );
+        assert!(lanes.push(TwinPriority::Motion, wheels(DRIVING)).is_ok());
 
-    pub fn channel(&self) -> u8 {
-        self.channel
+        assert_eq!(wheel_inputs(lanes.pop()), [WHEEL_STOPPED; 4]);
+        assert_eq!(wheel_inputs(lanes.pop()), DRIVING);
     }
 
-    pub fn value(&self) -> u8 {
-        self.value
+    #[test]
+    fn full_lanes_hand_the_command_back() {
+        let mut lanes = LaneState::new();
+        for _ in 0..TWIN_LANE_DEPTH {
+            let read = TwinCommand::new(TwinRequest::ReadHeadlight(Headlight::Left));
+            assert!(lanes.push(TwinPriority::Lighting, read).is_ok());
+        }
+        let read = TwinCommand::new(TwinRequest::ReadHeadlight(Headlight::Right));
+        assert!(lanes.push(TwinPriority::Lighting, read).is_err());
+        // Other lanes still have room
+        assert!(lanes.push(TwinPriority::Motion, wheels(DRIVING)).is_ok());
     }
 }